@@ -0,0 +1,206 @@
+//! Compares a direct connection to a backend with one that goes through pgdproxy.
+//!
+//! The backend is a fake postgres running in-process that answers every simple query with a
+//! fixed number of rows, so the numbers only reflect what the proxy adds.
+//!
+//! Run with `cargo bench --bench forwarding`.
+
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use pgdproxy::listener::{self, Listener};
+use pgdproxy::pg_codec::{
+    BackendMessage, ClientFrame, FieldDescription, ForwardingBackendCodec, ForwardingClientCodec,
+    FrameInfo, FrontendMessage,
+};
+use postgres_protocol::message::frontend;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+use tokio_util::codec::Decoder;
+
+const LATENCY_ITERATIONS: usize = 2000;
+const THROUGHPUT_ROWS: usize = 100_000;
+const THROUGHPUT_ITERATIONS: usize = 5;
+
+// A postgres that trusts everyone and answers "rows N" with N rows of 100 bytes
+async fn fake_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve(socket));
+        }
+    });
+    port
+}
+
+async fn serve(mut socket: TcpStream) {
+    let len = socket.read_i32().await.unwrap();
+    let mut startup = vec![0; len as usize - 4];
+    socket.read_exact(&mut startup).await.unwrap();
+
+    let mut conn = ForwardingClientCodec::default().framed(socket);
+    conn.feed(BackendMessage::AuthenticationOk.encode())
+        .await
+        .unwrap();
+    conn.feed(
+        BackendMessage::BackendKeyData {
+            process_id: 1,
+            secret_key: 1,
+        }
+        .encode(),
+    )
+    .await
+    .unwrap();
+    conn.send(BackendMessage::ReadyForQuery { status: b'I' }.encode())
+        .await
+        .unwrap();
+
+    let value = Bytes::from(vec![b'x'; 100]);
+    while let Some(Ok(frame)) = conn.next().await {
+        match frame.message().unwrap() {
+            FrontendMessage::Query { query } => {
+                let rows: usize = query.trim_start_matches("rows ").parse().unwrap();
+                conn.feed(
+                    BackendMessage::RowDescription {
+                        fields: vec![FieldDescription::text("x")],
+                    }
+                    .encode(),
+                )
+                .await
+                .unwrap();
+                for _ in 0..rows {
+                    conn.feed(
+                        BackendMessage::DataRow {
+                            values: vec![Some(value.clone())],
+                        }
+                        .encode(),
+                    )
+                    .await
+                    .unwrap();
+                }
+                conn.feed(
+                    BackendMessage::CommandComplete {
+                        tag: format!("SELECT {rows}"),
+                    }
+                    .encode(),
+                )
+                .await
+                .unwrap();
+                conn.send(BackendMessage::ReadyForQuery { status: b'I' }.encode())
+                    .await
+                    .unwrap();
+            }
+            FrontendMessage::Terminate => return,
+            _ => {}
+        }
+    }
+}
+
+async fn proxy(target_port: u16) -> u16 {
+    // Listener::start wants an address, so find a free port for it
+    let port = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let (s, r) = oneshot::channel();
+    tokio::spawn(async move {
+        Listener::start(listener::Config {
+            binding: format!("127.0.0.1:{port}"),
+            target_address: format!("127.0.0.1:{target_port}"),
+            ch: Some(s),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    });
+    r.await.unwrap();
+    port
+}
+
+struct Client {
+    conn: tokio_util::codec::Framed<TcpStream, ForwardingBackendCodec>,
+}
+
+impl Client {
+    async fn connect(port: u16) -> Self {
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        socket.set_nodelay(true).unwrap();
+        let mut startup = BytesMut::new();
+        frontend::startup_message([("user", "bench")], &mut startup).unwrap();
+        socket.write_all(&startup).await.unwrap();
+        let mut client = Client {
+            conn: ForwardingBackendCodec::default().framed(socket),
+        };
+        client.wait_for_ready().await;
+        client
+    }
+
+    // Returns the number of bytes received
+    async fn query(&mut self, rows: usize) -> usize {
+        let mut buf = BytesMut::new();
+        frontend::query(&format!("rows {rows}"), &mut buf).unwrap();
+        self.conn.get_mut().write_all(&buf).await.unwrap();
+        self.wait_for_ready().await
+    }
+
+    async fn wait_for_ready(&mut self) -> usize {
+        let mut bytes = 0;
+        while let Some(frame) = self.conn.next().await {
+            let frame = frame.unwrap();
+            bytes += frame.as_bytes().len();
+            if frame.done() {
+                break;
+            }
+        }
+        bytes
+    }
+}
+
+async fn measure(name: &str, port: u16) {
+    let mut client = Client::connect(port).await;
+
+    let mut latencies = Vec::with_capacity(LATENCY_ITERATIONS);
+    for _ in 0..LATENCY_ITERATIONS {
+        let start = Instant::now();
+        client.query(1).await;
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+    let mean = latencies.iter().sum::<Duration>() / LATENCY_ITERATIONS as u32;
+    let p99 = latencies[LATENCY_ITERATIONS * 99 / 100];
+
+    let start = Instant::now();
+    let mut bytes = 0;
+    for _ in 0..THROUGHPUT_ITERATIONS {
+        bytes += client.query(THROUGHPUT_ROWS).await;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let rows_per_sec = (THROUGHPUT_ROWS * THROUGHPUT_ITERATIONS) as f64 / elapsed;
+    let mb_per_sec = bytes as f64 / elapsed / (1024.0 * 1024.0);
+
+    println!(
+        "{name:<8} latency mean {:>8.1?} p99 {:>8.1?} | throughput {:>10.0} rows/s {:>8.1} MiB/s",
+        mean, p99, rows_per_sec, mb_per_sec
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let backend_port = fake_backend().await;
+        let proxy_port = proxy(backend_port).await;
+        measure("direct", backend_port).await;
+        measure("pgdproxy", proxy_port).await;
+    });
+}
@@ -0,0 +1,349 @@
+use std::{io, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task,
+};
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+use crate::session::{Reason, Resume, Session, Sessions};
+
+// Requests are a line and a few headers, anything bigger is a mistake
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// What the admin interface can see and do
+#[derive(Debug)]
+pub struct Admin {
+    pub metrics: Metrics,
+    pub sessions: Sessions,
+}
+
+/// Serves the admin interface, a bare bones HTTP/1.1 server with one request per connection
+pub async fn serve(listener: TcpListener, admin: Admin) {
+    let admin = Arc::new(admin);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let admin = admin.clone();
+                task::spawn(async move {
+                    if let Err(e) = handle(stream, &admin).await {
+                        debug!(error = %e, "Error handling admin request");
+                    }
+                });
+            }
+            Err(e) => warn!(error = %e, "Error accepting admin connection"),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body,
+}
+
+enum Body {
+    Full(String),
+    /// Lines sent as they come, until the sender goes away or the client does
+    Stream(broadcast::Receiver<String>),
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: "200 OK",
+            content_type,
+            body: Body::Full(body),
+        }
+    }
+
+    fn stream(lines: broadcast::Receiver<String>) -> Self {
+        Response {
+            status: "200 OK",
+            content_type: "application/x-ndjson",
+            body: Body::Stream(lines),
+        }
+    }
+
+    fn json(body: serde_json::Value) -> Self {
+        Response::ok("application/json", format!("{body}\n"))
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: Body::Full(format!("{status}\n")),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, admin: &Admin) -> io::Result<()> {
+    let response = match read_request(&mut stream).await? {
+        Some(request) => route(&request, admin).await,
+        None => Response::error("400 Bad Request"),
+    };
+    match response.body {
+        Body::Full(body) => {
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.status,
+                response.content_type,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body.as_bytes()).await?;
+        }
+        Body::Stream(mut lines) => {
+            // No length, the body ends when the connection does
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+                response.status, response.content_type
+            );
+            stream.write_all(head.as_bytes()).await?;
+            let mut unused = [0u8; 64];
+            loop {
+                let line = tokio::select! {
+                    line = lines.recv() => line,
+                    // Reading is how we find out the client hung up, so the session stops
+                    // counting it as a reader
+                    n = stream.read(&mut unused) => match n {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => continue,
+                    },
+                };
+                match line {
+                    Ok(line) => {
+                        stream.write_all(line.as_bytes()).await?;
+                        stream.write_all(b"\n").await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Tail fell behind, skipping entries");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+    stream.shutdown().await
+}
+
+async fn route(request: &Request, admin: &Admin) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics"]) => Response::ok(
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            admin.metrics.encode(),
+        ),
+        ("GET", ["sessions"]) => {
+            let sessions = admin.sessions.list().await;
+            Response::json(sessions.iter().map(|s| session_json(s)).collect())
+        }
+        // Recording for every session, now and later
+        ("POST", ["record"]) => match admin.sessions.record_all(true).await {
+            Ok(()) => Response::json(serde_json::json!({ "recording": true })),
+            Err(e) => {
+                warn!(error = %e, "Error starting recording");
+                Response::error("500 Internal Server Error")
+            }
+        },
+        ("DELETE", ["record"]) => {
+            // Stopping can't fail
+            let _ = admin.sessions.record_all(false).await;
+            Response::json(serde_json::json!({ "recording": false }))
+        }
+        (method, ["sessions", id, rest @ ..]) => {
+            let Some(session) = session(admin, id).await else {
+                return Response::error("404 Not Found");
+            };
+            match (method, rest) {
+                ("GET", []) => Response::json(session_json(&session)),
+                ("POST", ["record"]) => match session.start_recording().await {
+                    Ok(path) => Response::json(serde_json::json!({ "path": path })),
+                    Err(e) => {
+                        warn!(error = %e, "Error starting recording");
+                        Response::error("500 Internal Server Error")
+                    }
+                },
+                ("DELETE", ["record"]) => {
+                    Response::json(serde_json::json!({ "path": session.stop_recording().await }))
+                }
+                ("GET", ["tail"]) => Response::stream(session.tail()),
+                // Lets an app request held at a breakpoint go on
+                ("POST", ["continue"]) => {
+                    if session.resume(Resume::Continue) {
+                        Response::json(serde_json::json!({ "continued": true }))
+                    } else {
+                        Response::error("409 Conflict")
+                    }
+                }
+                // A request that failed already ran, there's nothing to skip
+                ("POST", ["skip"]) => {
+                    let reason = session.paused().map(|pause| pause.reason);
+                    if !matches!(reason, None | Some(Reason::Error { .. }))
+                        && session.resume(Resume::Skip)
+                    {
+                        Response::json(serde_json::json!({ "skipped": true }))
+                    } else {
+                        Response::error("409 Conflict")
+                    }
+                }
+                // Only a COMMIT we have the SQL of can be turned into a ROLLBACK
+                ("POST", ["rollback"]) => {
+                    let reason = session.paused().map(|pause| pause.reason);
+                    if reason == Some(Reason::Commit { rollback: true })
+                        && session.resume(Resume::Rollback)
+                    {
+                        Response::json(serde_json::json!({ "rolled_back": true }))
+                    } else {
+                        Response::error("409 Conflict")
+                    }
+                }
+                ("GET", ["watches"]) => {
+                    Response::json(serde_json::json!({ "watches": session.watches() }))
+                }
+                // The body is the SQL to run after each app request
+                ("POST", ["watches"]) => match request.body.trim() {
+                    "" => Response::error("400 Bad Request"),
+                    sql => {
+                        session.watch(sql.to_string());
+                        Response::json(serde_json::json!({ "watches": session.watches() }))
+                    }
+                },
+                ("DELETE", ["watches"]) => {
+                    Response::json(serde_json::json!({ "watches": session.unwatch() }))
+                }
+                ("POST", ["step"]) => {
+                    session.set_stepping(true);
+                    Response::json(serde_json::json!({ "stepping": true }))
+                }
+                ("DELETE", ["step"]) => {
+                    session.set_stepping(false);
+                    Response::json(serde_json::json!({ "stepping": false }))
+                }
+                (
+                    _,
+                    []
+                    | ["record"]
+                    | ["tail"]
+                    | ["continue"]
+                    | ["skip"]
+                    | ["rollback"]
+                    | ["step"]
+                    | ["watches"],
+                ) => Response::error("405 Method Not Allowed"),
+                _ => Response::error("404 Not Found"),
+            }
+        }
+        (_, ["metrics" | "sessions" | "record"]) => Response::error("405 Method Not Allowed"),
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+// Sessions can be named by id or by the label the app gave them
+async fn session(admin: &Admin, key: &str) -> Option<Arc<Session>> {
+    admin.sessions.find(&percent_decode(key)?).await
+}
+
+/// Escapes everything but letters, digits and `-._~`, so a label can go in a path segment
+pub fn path_segment(s: &str) -> String {
+    let mut segment = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            segment.push(b as char);
+        } else {
+            segment.push_str(&format!("%{b:02X}"));
+        }
+    }
+    segment
+}
+
+/// Undoes `path_segment`, or any other percent-encoding. `None` if it's malformed.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&b, after)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(b);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn session_json(session: &Session) -> serde_json::Value {
+    serde_json::json!({
+        "id": session.id,
+        "peer": session.peer.to_string(),
+        "label": session.label(),
+        "debug_port": session.debug_port(),
+        "recording": session.recording_path(),
+        "paused": session.paused(),
+        "stepping": session.is_stepping(),
+        "watches": session.watches(),
+    })
+}
+
+/// Reads the headers, and the body if there's a Content-Length. `None` if it isn't something we
+/// understand.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head_len = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(buf.len())
+        + 4;
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if head_len + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+    while buf.len() < head_len + content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(&buf[head_len..head_len + content_length]).into_owned();
+    let mut parts = head.lines().next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    // We don't take any query parameters
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+    }))
+}
@@ -0,0 +1,1356 @@
+use std::{cmp::Ordering, collections::HashMap, sync::OnceLock};
+
+use bytes::Bytes;
+use postgres_protocol::{types, Oid};
+use regex::Regex;
+
+use crate::pg_codec::{BackendMessage, ClientCommand, FieldDescription, FrontendMessage};
+use crate::query_log;
+use crate::session::{Pause, Reason, Resume};
+use crate::tap::Tap;
+
+/// Patterns that pause an app request before it's sent to the target
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+}
+
+/// A pattern for the SQL or prepared statement name, and what its parameters have to be
+#[derive(Debug, Clone)]
+struct Breakpoint {
+    /// As it was given
+    spec: String,
+    pattern: Regex,
+    conditions: Vec<Condition>,
+}
+
+/// `$2 = 42`, or `user_id = 42` for the parameter the SQL compares `user_id` to
+#[derive(Debug, Clone)]
+struct Condition {
+    param: Param,
+    op: Ordering,
+    negated: bool,
+    /// `None` for NULL
+    value: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Param {
+    /// Counting from 1, like `$1`
+    Position(usize),
+    /// Finds the parameter the SQL compares the column to, see `column_pattern`
+    Column(Regex),
+}
+
+impl Breakpoints {
+    /// Each breakpoint is a regex, optionally followed by `{if}` and conditions on the parameters
+    /// joined with `and`, e.g. `from orders {if} $1 = 42 and status = 'new'`
+    pub fn new(specs: &[String]) -> Result<Self, String> {
+        Ok(Breakpoints {
+            breakpoints: specs
+                .iter()
+                .map(|spec| Breakpoint::new(spec))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// The first breakpoint that a statement the request executes matches, by its SQL or its
+    /// prepared statement's name, and its parameters
+    pub(crate) fn hit(&self, request: &[ClientCommand], tap: &Tap) -> Option<Pause> {
+        executed(request, tap)
+            .into_iter()
+            .find_map(|statement| self.matching(statement))
+    }
+
+    fn matching(&self, executed: Executed) -> Option<Pause> {
+        // The unnamed statement has no name to match
+        let statement = executed
+            .statement
+            .as_deref()
+            .filter(|name| !name.is_empty());
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| {
+                let pattern = &breakpoint.pattern;
+                (pattern.is_match(&executed.sql)
+                    || statement.is_some_and(|name| pattern.is_match(name)))
+                    && breakpoint
+                        .conditions
+                        .iter()
+                        .all(|condition| condition.holds(&executed.sql, &executed.params))
+            })
+            .map(|breakpoint| Pause {
+                reason: Reason::Breakpoint {
+                    breakpoint: breakpoint.spec.clone(),
+                },
+                sql: executed.sql.clone(),
+                params: executed.params.clone(),
+            })
+    }
+}
+
+impl Breakpoint {
+    fn new(spec: &str) -> Result<Self, String> {
+        let (pattern, conditions) = match split_conditions(spec) {
+            (pattern, Some(conditions)) => (pattern, Condition::parse_all(conditions)?),
+            (pattern, None) => (pattern, vec![]),
+        };
+        Ok(Breakpoint {
+            spec: spec.to_string(),
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            conditions,
+        })
+    }
+}
+
+/// Splits a breakpoint at the `{if}` before its conditions. That's never valid in a regex, a
+/// literal one is written `\{if}`, so it can't be part of the pattern, like an `if` in the SQL
+/// can.
+fn split_conditions(spec: &str) -> (&str, Option<&str>) {
+    let mut from = 0;
+    while let Some(at) = spec[from..].find("{if}") {
+        let at = from + at;
+        if !spec[..at].ends_with('\\') {
+            return (spec[..at].trim_end(), Some(&spec[at + 4..]));
+        }
+        from = at + 4;
+    }
+    (spec, None)
+}
+
+impl Condition {
+    /// Conditions joined with `and`
+    fn parse_all(s: &str) -> Result<Vec<Self>, String> {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(
+                r"^\s*(\$\d+|[A-Za-z_][A-Za-z0-9_]*)\s*(=|!=|<>|<=|>=|<|>)\s*('(?:[^']|'')*'|[^\s']+)\s*(?:(?i:and)\s+|$)",
+            )
+            .unwrap()
+        });
+        let mut conditions = vec![];
+        let mut rest = s;
+        while !rest.trim().is_empty() {
+            let captures = pattern
+                .captures(rest)
+                .ok_or_else(|| format!("can't parse breakpoint condition: {}", rest.trim()))?;
+            let param = match captures[1].strip_prefix('$') {
+                Some(n) => match n.parse() {
+                    Ok(n) if n > 0 => Param::Position(n),
+                    _ => return Err(format!("no such parameter: {}", &captures[1])),
+                },
+                None => Param::Column(column_pattern(&captures[1])),
+            };
+            let (op, negated) = match &captures[2] {
+                "=" => (Ordering::Equal, false),
+                "!=" | "<>" => (Ordering::Equal, true),
+                "<" => (Ordering::Less, false),
+                ">=" => (Ordering::Less, true),
+                ">" => (Ordering::Greater, false),
+                _ => (Ordering::Greater, true),
+            };
+            let value = match &captures[3] {
+                value if value.eq_ignore_ascii_case("null") => None,
+                value => Some(match value.strip_prefix('\'') {
+                    Some(quoted) => quoted[..quoted.len() - 1].replace("''", "'"),
+                    None => value.to_string(),
+                }),
+            };
+            conditions.push(Condition {
+                param,
+                op,
+                negated,
+                value,
+            });
+            rest = &rest[captures[0].len()..];
+        }
+        Ok(conditions)
+    }
+
+    /// Whether the statement's parameters meet the condition. Like in SQL, NULL is only ever
+    /// equal (or not) to NULL, and a parameter that isn't there meets nothing.
+    fn holds(&self, sql: &str, params: &[Option<String>]) -> bool {
+        let position = match &self.param {
+            Param::Position(n) => Some(*n),
+            Param::Column(pattern) => column_param(sql, pattern),
+        };
+        let Some(param) = position.and_then(|n| params.get(n - 1)) else {
+            return false;
+        };
+        let ordering = match (param, &self.value) {
+            (None, None) => Ordering::Equal,
+            // Only = and != mean anything here
+            (Some(_), None) => return self.op == Ordering::Equal && self.negated,
+            (None, Some(_)) => return false,
+            (Some(param), Some(value)) => compare(param, value),
+        };
+        (ordering == self.op) != self.negated
+    }
+}
+
+/// Matches SQL comparing a column to a parameter, as in `user_id = $3`, capturing the parameter
+fn column_pattern(column: &str) -> Regex {
+    let column = regex::escape(column);
+    // The column is escaped, so this always compiles
+    Regex::new(&format!(
+        r"(?i)\b{column}\s*(?:=|!=|<>|<=|>=|<|>)\s*\$(\d+)|\$(\d+)\s*(?:=|!=|<>|<=|>=|<|>)\s*(?:\w+\.)?{column}\b"
+    ))
+    .unwrap()
+}
+
+/// Which parameter some SQL compares a column to, with a pattern from `column_pattern`
+fn column_param(sql: &str, pattern: &Regex) -> Option<usize> {
+    let sql = unquoted(sql);
+    let captures = pattern.captures(&sql)?;
+    captures.get(1).or(captures.get(2))?.as_str().parse().ok()
+}
+
+/// Numbers as numbers, booleans as booleans, anything else as text
+fn compare(param: &str, value: &str) -> Ordering {
+    if let (Ok(param), Ok(value)) = (param.trim().parse::<f64>(), value.parse::<f64>()) {
+        return param.partial_cmp(&value).unwrap_or(Ordering::Equal);
+    }
+    if let (Some(param), Some(value)) = (boolean(param), boolean(value)) {
+        return param.cmp(&value);
+    }
+    param.cmp(value)
+}
+
+fn boolean(s: &str) -> Option<bool> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "t" | "true" => Some(true),
+        "f" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// A statement a request executes
+struct Executed {
+    sql: String,
+    /// The prepared statement's name, for the extended protocol
+    statement: Option<String>,
+    /// Decoded, see `decode_params`
+    params: Vec<Option<String>>,
+}
+
+/// The statements a request executes. Statements that are only prepared don't count.
+/// Statements prepared by earlier requests, and their parameter types, are looked up in what
+/// the tap has seen.
+fn executed(request: &[ClientCommand], tap: &Tap) -> Vec<Executed> {
+    // Statement names to SQL and parameter types, and portals to statement names and
+    // parameters, from this request
+    let mut statements = HashMap::new();
+    let mut portals = HashMap::new();
+    let mut executed = vec![];
+    for message in request {
+        let Ok(message) = FrontendMessage::parse(message) else {
+            continue;
+        };
+        match message {
+            FrontendMessage::Query { query } => executed.push(Executed {
+                sql: query,
+                statement: None,
+                params: vec![],
+            }),
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            } => {
+                statements.insert(name, (query, param_types));
+            }
+            FrontendMessage::Bind {
+                portal,
+                statement,
+                param_formats,
+                params,
+                ..
+            } => {
+                let types = match statements.get(&statement) {
+                    Some((_, types)) => types.as_slice(),
+                    None => tap.param_types(&statement).unwrap_or_default(),
+                };
+                let params = decode_params(types, &param_formats, &params);
+                portals.insert(portal, (statement, params));
+            }
+            FrontendMessage::Execute { portal, .. } => {
+                let Some((statement, params)) = portals.get(&portal) else {
+                    continue;
+                };
+                let sql = statements
+                    .get(statement)
+                    .map(|(sql, _)| sql.as_str())
+                    .or_else(|| tap.statement(statement));
+                if let Some(sql) = sql {
+                    executed.push(Executed {
+                        sql: sql.to_string(),
+                        statement: Some(statement.clone()),
+                        params: params.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    executed
+}
+
+/// A Bind's parameters as text. Binary ones are decoded if we know their type and how, and
+/// shown as hex if not.
+fn decode_params(types: &[Oid], formats: &[i16], params: &[Option<Bytes>]) -> Vec<Option<String>> {
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let format = query_log::param_format(formats, i);
+            let value = param.as_ref()?;
+            let oid = types.get(i).copied().unwrap_or(0);
+            Some(match format {
+                0 => String::from_utf8_lossy(value).into_owned(),
+                _ => decode_binary(oid, value)
+                    .unwrap_or_else(|| query_log::format_param(format, value)),
+            })
+        })
+        .collect()
+}
+
+/// A binary parameter as postgres would print it, for the common types
+fn decode_binary(oid: Oid, value: &[u8]) -> Option<String> {
+    Some(match oid {
+        BOOL => (if types::bool_from_sql(value).ok()? {
+            "t"
+        } else {
+            "f"
+        })
+        .to_string(),
+        INT2 => types::int2_from_sql(value).ok()?.to_string(),
+        INT4 => types::int4_from_sql(value).ok()?.to_string(),
+        INT8 => types::int8_from_sql(value).ok()?.to_string(),
+        OID => types::oid_from_sql(value).ok()?.to_string(),
+        FLOAT4 => types::float4_from_sql(value).ok()?.to_string(),
+        FLOAT8 => types::float8_from_sql(value).ok()?.to_string(),
+        TEXT | VARCHAR | BPCHAR | NAME | JSON => types::text_from_sql(value).ok()?.to_string(),
+        // A version byte, then the text
+        JSONB => types::text_from_sql(value.strip_prefix(&[1])?)
+            .ok()?
+            .to_string(),
+        UUID => {
+            let hex: String = types::uuid_from_sql(value)
+                .ok()?
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            format!(
+                "{}-{}-{}-{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..]
+            )
+        }
+        NUMERIC => decode_numeric(value)?,
+        DATE => match types::date_from_sql(value).ok()? {
+            i32::MAX => "infinity".to_string(),
+            i32::MIN => "-infinity".to_string(),
+            days => format_date(days.into(), ""),
+        },
+        // Shown in UTC, we don't know the session's time zone
+        TIMESTAMP | TIMESTAMPTZ => match types::timestamp_from_sql(value).ok()? {
+            i64::MAX => "infinity".to_string(),
+            i64::MIN => "-infinity".to_string(),
+            micros => {
+                let days = micros.div_euclid(86_400_000_000);
+                let micros = micros.rem_euclid(86_400_000_000);
+                let seconds = micros / 1_000_000;
+                let mut time = format!(
+                    "{:02}:{:02}:{:02}",
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                );
+                if micros % 1_000_000 != 0 {
+                    let fraction = format!(".{:06}", micros % 1_000_000);
+                    time.push_str(fraction.trim_end_matches('0'));
+                }
+                if oid == TIMESTAMPTZ {
+                    time.push_str("+00");
+                }
+                format_date(days, &format!(" {time}"))
+            }
+        },
+        _ => return None,
+    })
+}
+
+/// A binary numeric: the number of base 10000 digits, the weight of the first, the sign and
+/// the number of decimal digits to show, and then the digits
+fn decode_numeric(mut value: &[u8]) -> Option<String> {
+    let mut next = || -> Option<i16> {
+        let (word, rest) = value.split_first_chunk::<2>()?;
+        value = rest;
+        Some(i16::from_be_bytes(*word))
+    };
+    let (ndigits, weight, sign, dscale) = (next()?, next()?, next()? as u16, next()?);
+    let digits = (0..ndigits).map(|_| next()).collect::<Option<Vec<_>>>()?;
+    let mut out = match sign {
+        0x0000 => String::new(),
+        0x4000 => "-".to_string(),
+        0xC000 => return Some("NaN".to_string()),
+        0xD000 => return Some("Infinity".to_string()),
+        0xF000 => return Some("-Infinity".to_string()),
+        _ => return None,
+    };
+    let digit = |i: i32| -> i16 {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| digits.get(i).copied())
+            .unwrap_or(0)
+    };
+    let weight = i32::from(weight);
+    if weight < 0 {
+        out.push('0');
+    }
+    for i in 0..=weight {
+        match i {
+            0 => out.push_str(&digit(i).to_string()),
+            _ => out.push_str(&format!("{:04}", digit(i))),
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut i = weight + 1;
+        while fraction.len() < dscale as usize {
+            fraction.push_str(&format!("{:04}", digit(i)));
+            i += 1;
+        }
+        out.push('.');
+        out.push_str(&fraction[..dscale as usize]);
+    }
+    Some(out)
+}
+
+/// A day counted from 2000-01-01 as postgres prints it, with `time` before any BC
+fn format_date(days: i64, time: &str) -> String {
+    // Howard Hinnant's days_from_civil, backwards, counting from 0000-03-01
+    let days = days + 730_425;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    // There's no year 0, 1 BC comes right before 1 AD
+    match year {
+        ..=0 => format!("{:04}-{month:02}-{day:02}{time} BC", 1 - year),
+        _ => format!("{year:04}-{month:02}-{day:02}{time}"),
+    }
+}
+
+// Type OIDs, from pg_type.dat
+const BOOL: Oid = 16;
+const NAME: Oid = 19;
+const INT8: Oid = 20;
+const INT2: Oid = 21;
+const INT4: Oid = 23;
+const TEXT: Oid = 25;
+const OID: Oid = 26;
+const JSON: Oid = 114;
+const FLOAT4: Oid = 700;
+const FLOAT8: Oid = 701;
+const BPCHAR: Oid = 1042;
+const VARCHAR: Oid = 1043;
+const DATE: Oid = 1082;
+const TIMESTAMP: Oid = 1114;
+const TIMESTAMPTZ: Oid = 1184;
+const NUMERIC: Oid = 1700;
+const UUID: Oid = 2950;
+const JSONB: Oid = 3802;
+
+/// Where the COMMIT, END and COMMIT PREPARED keywords are in some SQL. Only the start of a
+/// statement counts, and quoted strings, dollar quoted bodies and comments are passed over, so a
+/// function ending in END doesn't count. Nor does the END closing a `BEGIN ATOMIC` body.
+fn commits(sql: &str) -> Vec<std::ops::Range<usize>> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    static ATOMIC: OnceLock<Regex> = OnceLock::new();
+    // An empty body's END follows ATOMIC, rather than the start of a statement
+    let pattern =
+        PATTERN.get_or_init(|| Regex::new(r"(?i)(?:^|;|\batomic)\s*(commit|end)\b").unwrap());
+    let atomic = ATOMIC.get_or_init(|| Regex::new(r"(?i)\bbegin\s+atomic\b").unwrap());
+    let sql = unquoted(sql);
+    // Where the function body we're in starts, if we're in one
+    let mut body = atomic.find(&sql).map(|found| found.start());
+    let mut commits = vec![];
+    for keyword in pattern
+        .captures_iter(&sql)
+        .filter_map(|captures| captures.get(1))
+    {
+        match body {
+            Some(start) if keyword.start() > start => {
+                body = atomic
+                    .find_at(&sql, keyword.end())
+                    .map(|found| found.start());
+            }
+            _ => commits.push(keyword.range()),
+        }
+    }
+    commits
+}
+
+/// The SQL with everything quoted or commented out blanked, byte for byte
+fn unquoted(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut i = 0;
+    while let Some(c) = sql[i..].chars().next() {
+        let rest = &sql[i..];
+        let end = if rest.starts_with('\'') || rest.starts_with('"') {
+            let quote = &rest[..1];
+            rest[1..].find(quote).map(|n| n + 2)
+        } else if rest.starts_with("--") {
+            Some(rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            rest.find("*/").map(|n| n + 2)
+        } else if let Some(tag) = dollar_tag(rest) {
+            rest[tag.len()..].find(tag).map(|n| n + 2 * tag.len())
+        } else {
+            // Other characters are blanked too, so the offsets still line up
+            if c.is_ascii() {
+                out.push(c);
+            } else {
+                out.push_str(&" ".repeat(c.len_utf8()));
+            }
+            i += c.len_utf8();
+            continue;
+        };
+        // Unterminated, the rest is quoted
+        let end = end.unwrap_or(rest.len());
+        out.push_str(&" ".repeat(end));
+        i += end;
+    }
+    out
+}
+
+/// The `$tag$` that opens a dollar quoted string, if one starts here
+fn dollar_tag(s: &str) -> Option<&str> {
+    let body = s.strip_prefix('$')?;
+    let len = body.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))?;
+    let tag = &body[..len];
+    if !body[len..].starts_with('$') || tag.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(&s[..len + 2])
+}
+
+/// Stops a request that commits a transaction
+pub(crate) fn commit(request: &[ClientCommand], tap: &Tap) -> Option<Pause> {
+    executed(request, tap)
+        .into_iter()
+        .find(|executed| !commits(&executed.sql).is_empty())
+        .map(|executed| Pause {
+            reason: Reason::Commit {
+                rollback: rollback(request).is_ok(),
+            },
+            sql: executed.sql,
+            params: executed.params,
+        })
+}
+
+/// `SELECT pgdproxy.break('label')`, the label left out or not, with the label
+fn break_call(sql: &str) -> Option<Option<String>> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(r"(?is)^\s*select\s+pgdproxy\.break\s*\(\s*(?:'((?:[^']|'')*)')?\s*\)\s*;?\s*$")
+            .unwrap()
+    });
+    let captures = pattern.captures(sql)?;
+    Some(
+        captures
+            .get(1)
+            .map(|label| label.as_str().replace("''", "'")),
+    )
+}
+
+/// A `/* pgdproxy:break */` comment, optionally with a label like `/* pgdproxy:break retry */`
+fn break_comment(sql: &str) -> Option<Option<String>> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern =
+        PATTERN.get_or_init(|| Regex::new(r"/\*\s*pgdproxy:break\b\s*(.*?)\s*\*/").unwrap());
+    let captures = pattern.captures(sql)?;
+    Some(Some(captures[1].to_string()).filter(|label| !label.is_empty()))
+}
+
+/// A request that only calls `pgdproxy.break()`, which we answer ourselves: the target has no
+/// such function
+pub(crate) struct BreakCall {
+    /// What to answer with
+    pub responses: Vec<BackendMessage>,
+    /// The labels of the calls it executes, prepares don't count
+    pub executed: Vec<Option<String>>,
+    /// The break statements prepared once it's answered
+    pub statements: HashMap<String, Option<String>>,
+}
+
+/// The answer to a request that calls `pgdproxy.break()` and does nothing else, `None` for any
+/// other request. `statements` are the break statements the app prepared earlier, by name, with
+/// their labels. They only exist as far as the app knows, they were never sent to the target.
+pub(crate) fn break_call_request(
+    request: &[ClientCommand],
+    statements: &HashMap<String, Option<String>>,
+    status: u8,
+) -> Option<BreakCall> {
+    let row_description = || BackendMessage::RowDescription {
+        fields: vec![FieldDescription::text("break")],
+    };
+    let previous = statements;
+    let mut statements = statements.clone();
+    let mut portals = HashMap::new();
+    let mut responses = vec![];
+    let mut executed = vec![];
+    for message in request {
+        match FrontendMessage::parse(message).ok()? {
+            FrontendMessage::Query { query } => {
+                let label = break_call(&query)?;
+                responses.extend([
+                    row_description(),
+                    BackendMessage::DataRow {
+                        values: vec![label.clone().map(Bytes::from)],
+                    },
+                    BackendMessage::CommandComplete {
+                        tag: "SELECT 1".to_string(),
+                    },
+                    BackendMessage::ReadyForQuery { status },
+                ]);
+                executed.push(label);
+            }
+            FrontendMessage::Parse { name, query, .. } => {
+                statements.insert(name, break_call(&query)?);
+                responses.push(BackendMessage::ParseComplete);
+            }
+            FrontendMessage::Bind {
+                portal, statement, ..
+            } => {
+                portals.insert(portal, statements.get(&statement)?.clone());
+                responses.push(BackendMessage::BindComplete);
+            }
+            FrontendMessage::Describe { variant, name } => {
+                if variant == b'S' {
+                    statements.get(&name)?;
+                    responses.push(BackendMessage::ParameterDescription { types: vec![] });
+                } else {
+                    portals.get(&name)?;
+                }
+                responses.push(row_description());
+            }
+            FrontendMessage::Execute { portal, .. } => {
+                let label = portals.get(&portal)?.clone();
+                responses.extend([
+                    BackendMessage::DataRow {
+                        values: vec![label.clone().map(Bytes::from)],
+                    },
+                    BackendMessage::CommandComplete {
+                        tag: "SELECT 1".to_string(),
+                    },
+                ]);
+                executed.push(label);
+            }
+            FrontendMessage::Close { variant, name } => {
+                if variant == b'S' {
+                    statements.remove(&name)?;
+                } else {
+                    portals.remove(&name)?;
+                }
+                responses.push(BackendMessage::CloseComplete);
+            }
+            FrontendMessage::Sync => responses.push(BackendMessage::ReadyForQuery { status }),
+            FrontendMessage::Flush => {}
+            _ => return None,
+        }
+    }
+    if status == b'E' {
+        return Some(failed_break_call(request, previous));
+    }
+    Some(BreakCall {
+        responses,
+        executed,
+        statements,
+    })
+}
+
+/// What postgres answers in a failed transaction: each simple query fails, and an extended
+/// query fails at its first message and is skipped up to its Sync. Nothing runs or is prepared.
+fn failed_break_call(
+    request: &[ClientCommand],
+    statements: &HashMap<String, Option<String>>,
+) -> BreakCall {
+    let error = || {
+        BackendMessage::error(
+            "25P02",
+            "current transaction is aborted, commands ignored until end of transaction block",
+        )
+    };
+    let ready = || BackendMessage::ReadyForQuery { status: b'E' };
+    let mut responses = vec![];
+    let mut skipping = false;
+    for message in request {
+        match message[0] {
+            b'Q' => responses.extend([error(), ready()]),
+            b'S' => {
+                responses.push(ready());
+                skipping = false;
+            }
+            b'H' => {}
+            _ if !skipping => {
+                responses.push(error());
+                skipping = true;
+            }
+            _ => {}
+        }
+    }
+    BreakCall {
+        responses,
+        executed: vec![],
+        statements: statements.clone(),
+    }
+}
+
+/// Where the app asked to stop, with `pgdproxy.break()` or a `/* pgdproxy:break */` comment
+pub(crate) fn app_break(
+    request: &[ClientCommand],
+    tap: &Tap,
+    statements: &HashMap<String, Option<String>>,
+) -> Option<Pause> {
+    let call = break_call_request(request, statements, tap.transaction_status());
+    if let Some(label) = call.and_then(|call| call.executed.into_iter().next()) {
+        return Some(Pause {
+            reason: Reason::Break { label },
+            sql: describe(request, tap).unwrap_or_default(),
+            params: vec![],
+        });
+    }
+    executed(request, tap).into_iter().find_map(|executed| {
+        let label = break_comment(&executed.sql)?;
+        Some(Pause {
+            reason: Reason::Break { label },
+            sql: executed.sql,
+            params: executed.params,
+        })
+    })
+}
+
+/// Where step mode stops: any request that prepares or runs SQL. A lone Sync, say, goes through.
+pub(crate) fn step(request: &[ClientCommand], tap: &Tap) -> Option<Pause> {
+    describe(request, tap).map(|sql| Pause {
+        reason: Reason::Step,
+        sql,
+        params: vec![],
+    })
+}
+
+/// The SQL a request prepares or runs, `None` if it doesn't
+pub(crate) fn describe(request: &[ClientCommand], tap: &Tap) -> Option<String> {
+    let mut statements = HashMap::new();
+    let mut portals = HashMap::new();
+    let mut sql = vec![];
+    for message in request {
+        let Ok(message) = FrontendMessage::parse(message) else {
+            continue;
+        };
+        match message {
+            FrontendMessage::Query { query } => sql.push(query),
+            FrontendMessage::Parse { name, query, .. } => {
+                sql.push(query.clone());
+                statements.insert(name, query);
+            }
+            FrontendMessage::Bind {
+                portal, statement, ..
+            } => {
+                portals.insert(portal, statement);
+            }
+            FrontendMessage::Execute { portal, .. } => match portals.get(&portal) {
+                // Already in the list, from its Parse
+                Some(statement) if statements.contains_key(statement) => {}
+                Some(statement) => sql.push(
+                    tap.statement(statement)
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("prepared statement \"{statement}\"")),
+                ),
+                None => sql.push(format!("portal \"{portal}\"")),
+            },
+            _ => {}
+        }
+    }
+    (!sql.is_empty()).then(|| sql.join("; "))
+}
+
+/// What to send in place of a held request. Skipping turns a simple query into an empty one and
+/// leaves out an extended query's Executes, so its statements are prepared and bound but never
+/// run. Only a request with a single Query or Parse can be rewritten.
+pub(crate) fn apply(
+    request: &[ClientCommand],
+    resume: &Resume,
+) -> Result<Vec<ClientCommand>, String> {
+    let sql = match resume {
+        Resume::Continue => return Ok(request.to_vec()),
+        Resume::Skip => None,
+        Resume::Rewrite(sql) => Some(sql),
+        Resume::Rollback => return rollback(request),
+    };
+    let mut rewritten = 0;
+    let mut result = Vec::with_capacity(request.len());
+    for data in request {
+        let message = match (FrontendMessage::parse(data), sql) {
+            (Ok(FrontendMessage::Execute { .. }), None) => continue,
+            (Ok(FrontendMessage::Query { .. }), None) => FrontendMessage::Query {
+                query: String::new(),
+            },
+            (Ok(FrontendMessage::Query { .. }), Some(sql)) => {
+                rewritten += 1;
+                FrontendMessage::Query { query: sql.clone() }
+            }
+            (
+                Ok(FrontendMessage::Parse {
+                    name, param_types, ..
+                }),
+                Some(sql),
+            ) => {
+                rewritten += 1;
+                FrontendMessage::Parse {
+                    name,
+                    query: sql.clone(),
+                    param_types,
+                }
+            }
+            _ => {
+                result.push(data.clone());
+                continue;
+            }
+        };
+        result.push(message.encode().map_err(|e| e.to_string())?);
+    }
+    match (sql, rewritten) {
+        (Some(_), 0) => Err(
+            "pgdproxy: there's no SQL to rewrite, the request runs a statement prepared earlier"
+                .to_string(),
+        ),
+        (Some(_), n) if n > 1 => Err(format!(
+            "pgdproxy: can't rewrite a request with {n} statements"
+        )),
+        _ => Ok(result),
+    }
+}
+
+/// Turns the request's COMMITs into ROLLBACKs, and COMMIT PREPARED into ROLLBACK PREPARED. It
+/// has to have the SQL, a statement prepared by an earlier request can't be changed.
+fn rollback(request: &[ClientCommand]) -> Result<Vec<ClientCommand>, String> {
+    let rolled_back = |sql: &str| {
+        let mut sql = sql.to_string();
+        // Back to front, so the earlier ranges still hold
+        for keyword in commits(&sql).into_iter().rev() {
+            sql.replace_range(keyword, "ROLLBACK");
+        }
+        sql
+    };
+    let mut changed = false;
+    let mut result = Vec::with_capacity(request.len());
+    for data in request {
+        let message = match FrontendMessage::parse(data) {
+            Ok(FrontendMessage::Query { query }) if !commits(&query).is_empty() => {
+                FrontendMessage::Query {
+                    query: rolled_back(&query),
+                }
+            }
+            Ok(FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            }) if !commits(&query).is_empty() => FrontendMessage::Parse {
+                name,
+                query: rolled_back(&query),
+                param_types,
+            },
+            _ => {
+                result.push(data.clone());
+                continue;
+            }
+        };
+        changed = true;
+        result.push(message.encode().map_err(|e| e.to_string())?);
+    }
+    if changed {
+        Ok(result)
+    } else {
+        Err(
+            "pgdproxy: can't roll back, the COMMIT is a statement prepared earlier and can't be changed"
+                .to_string(),
+        )
+    }
+}
+
+/// Commands a debug client can give the proxy itself, as a simple query like
+/// `PGDPROXY CONTINUE`. They're answered by the proxy and never reach the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ProxyCommand {
+    /// `CONTINUE`, `SKIP`, `REWRITE <sql>` or `ROLLBACK`: let the held app request go on
+    Resume(Resume),
+    /// `STEP ON` or `STEP OFF`
+    Step(bool),
+    /// `WATCH <sql>`: run this after each app request
+    Watch(String),
+    /// `UNWATCH`: stop this session's watches
+    Unwatch,
+}
+
+impl ProxyCommand {
+    /// `None` if the query isn't meant for us. An error if it is, but we don't understand it.
+    pub fn parse(query: &str) -> Option<Result<Self, String>> {
+        let query = query.trim();
+        let (first, rest) = split_word(query);
+        if !first.eq_ignore_ascii_case("pgdproxy") {
+            return None;
+        }
+        let (command, rest) = split_word(rest);
+        // The SQL to rewrite to, or to watch, is kept as it is, semicolon and all
+        if command.eq_ignore_ascii_case("rewrite") {
+            return Some(match rest.trim() {
+                "" => Err("pgdproxy: REWRITE needs the SQL to run instead".to_string()),
+                sql => Ok(ProxyCommand::Resume(Resume::Rewrite(sql.to_string()))),
+            });
+        }
+        if command.eq_ignore_ascii_case("watch") {
+            return Some(match rest.trim() {
+                "" => Err("pgdproxy: WATCH needs the SQL to run".to_string()),
+                sql => Ok(ProxyCommand::Watch(sql.to_string())),
+            });
+        }
+        let command = command.to_ascii_lowercase();
+        let argument = rest
+            .trim()
+            .trim_end_matches(';')
+            .trim()
+            .to_ascii_lowercase();
+        Some(match (command.trim_end_matches(';'), argument.as_str()) {
+            ("continue", "") => Ok(ProxyCommand::Resume(Resume::Continue)),
+            ("skip", "") => Ok(ProxyCommand::Resume(Resume::Skip)),
+            ("rollback", "") => Ok(ProxyCommand::Resume(Resume::Rollback)),
+            ("step", "on") => Ok(ProxyCommand::Step(true)),
+            ("step", "off") => Ok(ProxyCommand::Step(false)),
+            ("unwatch", "") => Ok(ProxyCommand::Unwatch),
+            _ => Err(format!(
+                "pgdproxy: unknown command: {}",
+                query.trim_end_matches(';')
+            )),
+        })
+    }
+
+    /// The CommandComplete tag to answer with
+    pub fn tag(&self) -> &'static str {
+        match self {
+            ProxyCommand::Resume(Resume::Continue) => "CONTINUE",
+            ProxyCommand::Resume(Resume::Skip) => "SKIP",
+            ProxyCommand::Resume(Resume::Rewrite(_)) => "REWRITE",
+            ProxyCommand::Resume(Resume::Rollback) => "ROLLBACK",
+            ProxyCommand::Step(_) => "STEP",
+            ProxyCommand::Watch(_) => "WATCH",
+            ProxyCommand::Unwatch => "UNWATCH",
+        }
+    }
+}
+
+/// The first word, and what's after it
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    s.split_at(s.find(char::is_whitespace).unwrap_or(s.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(sql: &str) -> ClientCommand {
+        FrontendMessage::Query {
+            query: sql.to_string(),
+        }
+        .encode()
+        .unwrap()
+    }
+
+    fn sql_of(data: &ClientCommand) -> String {
+        match FrontendMessage::parse(data).unwrap() {
+            FrontendMessage::Query { query } | FrontendMessage::Parse { query, .. } => query,
+            message => panic!("no SQL in {message:?}"),
+        }
+    }
+
+    #[test]
+    fn commits_are_found_at_statement_starts() {
+        let found = |sql: &str| -> Vec<String> {
+            commits(sql)
+                .into_iter()
+                .map(|keyword| sql[keyword].to_string())
+                .collect()
+        };
+        assert_eq!(found("commit"), ["commit"]);
+        assert_eq!(found("insert into t values (1);\n  END;"), ["END"]);
+        assert_eq!(found("COMMIT PREPARED 'gid'"), ["COMMIT"]);
+        assert!(found("select 'commit'; -- end\n/* commit */ select \"end\"").is_empty());
+        assert!(found("do $$ begin perform 1; end $$").is_empty());
+        assert!(found("select case when true then 1 end").is_empty());
+        assert!(found("create function f() returns int begin atomic select 1; end").is_empty());
+        assert_eq!(
+            found("create function f() returns int BEGIN ATOMIC select 1; select 2; END; end"),
+            ["end"]
+        );
+        assert_eq!(
+            found("create function f() returns void begin atomic end; commit"),
+            ["commit"]
+        );
+    }
+
+    #[test]
+    fn commits_are_rolled_back() {
+        let rolled_back = rollback(&[query("insert into t values ('commit'); commit")]).unwrap();
+        assert_eq!(
+            sql_of(&rolled_back[0]),
+            "insert into t values ('commit'); ROLLBACK"
+        );
+        let rolled_back = rollback(&[query("commit prepared 'gid'")]).unwrap();
+        assert_eq!(sql_of(&rolled_back[0]), "ROLLBACK prepared 'gid'");
+
+        // Prepared in the same request
+        let parse = FrontendMessage::Parse {
+            name: "s1".to_string(),
+            query: "end".to_string(),
+            param_types: vec![],
+        };
+        let sync = FrontendMessage::Sync.encode().unwrap();
+        let rolled_back = rollback(&[parse.encode().unwrap(), sync.clone()]).unwrap();
+        assert_eq!(sql_of(&rolled_back[0]), "ROLLBACK");
+        assert_eq!(rolled_back[1], sync);
+
+        // Prepared earlier, there's nothing to change
+        let bind = FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "s1".to_string(),
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        };
+        let execute = FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        };
+        let request = [bind.encode().unwrap(), execute.encode().unwrap(), sync];
+        assert!(rollback(&request).is_err());
+        assert!(apply(&request, &Resume::Rollback).is_err());
+        assert_eq!(apply(&request, &Resume::Continue).unwrap(), request);
+    }
+
+    #[test]
+    fn columns_are_found_next_to_parameters() {
+        let param = |sql: &str| column_param(sql, &column_pattern("user_id"));
+        assert_eq!(param("select * from t where user_id = $2"), Some(2));
+        assert_eq!(param("select * from t where $3 <> t.USER_ID"), Some(3));
+        assert_eq!(param("select * from t where other_user_id = $1"), None);
+        assert_eq!(param("select 'user_id = $1' from t where id = $2"), None);
+    }
+
+    #[test]
+    fn binary_params_are_decoded() {
+        let decoded = |oid, hex: &str| {
+            let bytes: Vec<u8> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            decode_binary(oid, &bytes)
+        };
+        // From postgres' own *_send functions
+        let cases = [
+            (NUMERIC, "0003000140000003000109291a7c", "-12345.678"),
+            (NUMERIC, "0001ffff000000040005", "0.0005"),
+            (NUMERIC, "00010002000000000001", "100000000"),
+            (NUMERIC, "00000000c0000000", "NaN"),
+            (DATE, "00002279", "2024-02-29"),
+            (DATE, "fff49d7b", "0044-03-15 BC"),
+            (DATE, "7fffffff", "infinity"),
+            (TIMESTAMP, "0002b5843c59cd10", "2024-02-29 13:45:06.25"),
+            (TIMESTAMPTZ, "fffffffffff0bdc0", "1999-12-31 23:59:59+00"),
+        ];
+        for (oid, hex, expected) in cases {
+            assert_eq!(decoded(oid, hex).as_deref(), Some(expected), "{hex}");
+        }
+        assert_eq!(decoded(NUMERIC, "0003"), None);
+    }
+
+    fn tags(responses: &[BackendMessage]) -> String {
+        responses
+            .iter()
+            .map(|message| message.encode().as_bytes()[0] as char)
+            .collect()
+    }
+
+    fn param(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn patterns_are_split_from_conditions() {
+        assert_eq!(
+            split_conditions("^update users {if} $1 = 42"),
+            ("^update users", Some(" $1 = 42"))
+        );
+        assert_eq!(
+            split_conditions(r"a \{if} b{if}$1 = 1"),
+            (r"a \{if} b", Some("$1 = 1"))
+        );
+        // An if in the SQL is part of the pattern
+        let breakpoints = Breakpoints::new(&["drop table if exists".to_string()]).unwrap();
+        let breakpoint = &breakpoints.breakpoints[0];
+        assert!(breakpoint.conditions.is_empty());
+        assert!(breakpoint.pattern.is_match("drop table if exists t"));
+    }
+
+    #[test]
+    fn conditions_are_parsed() {
+        let conditions =
+            Condition::parse_all("$2 = 42 AND status <> 'it''s' and note = null").unwrap();
+        assert_eq!(conditions.len(), 3);
+        assert!(matches!(conditions[0].param, Param::Position(2)));
+        assert_eq!(
+            (conditions[0].op, conditions[0].negated),
+            (Ordering::Equal, false)
+        );
+        assert_eq!(conditions[0].value.as_deref(), Some("42"));
+        assert!(matches!(&conditions[1].param, Param::Column(_)));
+        assert_eq!(
+            (conditions[1].op, conditions[1].negated),
+            (Ordering::Equal, true)
+        );
+        assert_eq!(conditions[1].value.as_deref(), Some("it's"));
+        assert_eq!(conditions[2].value, None);
+
+        let ops: Vec<_> = ["<", "<=", ">", ">="]
+            .iter()
+            .map(|op| {
+                let condition = &Condition::parse_all(&format!("$1 {op} 1")).unwrap()[0];
+                (condition.op, condition.negated)
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (Ordering::Less, false),
+                (Ordering::Greater, true),
+                (Ordering::Greater, false),
+                (Ordering::Less, true),
+            ]
+        );
+
+        assert!(Condition::parse_all("$0 = 1").is_err());
+        assert!(Condition::parse_all("$1 ~ 1").is_err());
+        assert!(Condition::parse_all("$1 = 1 or $2 = 2").is_err());
+        assert!(Condition::parse_all("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn conditions_hold() {
+        let holds = |condition: &str, sql: &str, params: &[Option<String>]| {
+            Condition::parse_all(condition)
+                .unwrap()
+                .iter()
+                .all(|condition| condition.holds(sql, params))
+        };
+        let params = [param("42"), None, param("new")];
+        assert!(holds("$1 = 42", "", &params));
+        assert!(holds("$1 >= 42.0 and $1 < 100", "", &params));
+        assert!(!holds("$1 != 42", "", &params));
+        // NULL is only equal, or not, to NULL
+        assert!(holds("$2 = null", "", &params));
+        assert!(!holds("$2 != null", "", &params));
+        assert!(!holds("$2 = 1", "", &params));
+        assert!(!holds("$2 != 1", "", &params));
+        assert!(holds("$1 != null", "", &params));
+        assert!(!holds("$1 = null", "", &params));
+        // A parameter that isn't there meets nothing
+        assert!(!holds("$4 != 1", "", &params));
+        let sql = "update orders set status = $3 where id = $1";
+        assert!(holds("status = 'new' and id = 42", sql, &params));
+        assert!(!holds("customer = 42", sql, &params));
+    }
+
+    #[test]
+    fn params_are_compared_by_kind() {
+        assert_eq!(compare("10", "9"), Ordering::Greater);
+        assert_eq!(compare(" 1.50", "1.5"), Ordering::Equal);
+        assert_eq!(compare("t", "true"), Ordering::Equal);
+        assert_eq!(compare("FALSE", "t"), Ordering::Less);
+        assert_eq!(compare("10", "9a"), Ordering::Less);
+        assert_eq!(compare("b", "a"), Ordering::Greater);
+    }
+
+    #[test]
+    fn quoting_is_blanked() {
+        assert_eq!(unquoted("a 'b' c"), "a     c");
+        assert_eq!(unquoted("'it''s' x"), "        x");
+        assert_eq!(unquoted(r#"a "b;c" d"#), "a       d");
+        assert_eq!(unquoted("a -- b\nc"), "a     \nc");
+        assert_eq!(unquoted("a /* b */ c"), "a         c");
+        assert_eq!(unquoted("a $f$ b $f$ c $1"), "a           c $1");
+        assert_eq!(unquoted("a $$ b"), "a     ");
+        assert_eq!(unquoted("a 'unterminated"), "a              ");
+        // Byte for byte, so offsets line up
+        assert_eq!(unquoted("é = 'é'").len(), "é = 'é'".len());
+    }
+
+    #[test]
+    fn requests_are_applied() {
+        let request = [query("select 1")];
+        assert_eq!(apply(&request, &Resume::Continue).unwrap(), request);
+        assert_eq!(sql_of(&apply(&request, &Resume::Skip).unwrap()[0]), "");
+        let rewritten = apply(&request, &Resume::Rewrite("select 2".to_string())).unwrap();
+        assert_eq!(sql_of(&rewritten[0]), "select 2");
+        assert!(apply(
+            &[query("select 1"), query("select 2")],
+            &Resume::Rewrite("select 3".to_string())
+        )
+        .is_err());
+
+        let parse = FrontendMessage::Parse {
+            name: "s1".to_string(),
+            query: "select $1".to_string(),
+            param_types: vec![],
+        };
+        let bind = FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "s1".to_string(),
+            param_formats: vec![],
+            params: vec![Some(Bytes::from_static(b"1"))],
+            result_formats: vec![],
+        };
+        let execute = FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        };
+        let sync = FrontendMessage::Sync.encode().unwrap();
+        let request = [
+            parse.encode().unwrap(),
+            bind.encode().unwrap(),
+            execute.encode().unwrap(),
+            sync.clone(),
+        ];
+        // Prepared and bound, but never run
+        let skipped = apply(&request, &Resume::Skip).unwrap();
+        assert_eq!(
+            skipped,
+            [request[0].clone(), request[1].clone(), sync.clone()]
+        );
+        let rewritten = apply(&request, &Resume::Rewrite("select $1 + 1".to_string())).unwrap();
+        assert_eq!(sql_of(&rewritten[0]), "select $1 + 1");
+        assert_eq!(rewritten[1..], request[1..]);
+        assert!(apply(&request[1..], &Resume::Rewrite("select 2".to_string())).is_err());
+    }
+
+    #[test]
+    fn break_calls_are_recognised() {
+        assert_eq!(break_call("select pgdproxy.break()"), Some(None));
+        assert_eq!(
+            break_call("SELECT pgdproxy.break( 'it''s' );"),
+            Some(param("it's"))
+        );
+        assert_eq!(break_call("select pgdproxy.break(), 1"), None);
+        assert_eq!(
+            break_comment("select 1 /* pgdproxy:break retry */"),
+            Some(param("retry"))
+        );
+        assert_eq!(break_comment("select 1 /* pgdproxy:break */"), Some(None));
+        assert_eq!(break_comment("select 1 /* pgdproxy:breakage */"), None);
+    }
+
+    #[test]
+    fn break_calls_are_answered() {
+        let none = HashMap::new();
+        let call = break_call_request(&[query("select pgdproxy.break('a')")], &none, b'T').unwrap();
+        assert_eq!(tags(&call.responses), "TDCZ");
+        assert_eq!(call.executed, [param("a")]);
+        assert!(break_call_request(&[query("select 1")], &none, b'I').is_none());
+
+        // Prepared in one request, run in the next
+        let parse = FrontendMessage::Parse {
+            name: "b".to_string(),
+            query: "select pgdproxy.break()".to_string(),
+            param_types: vec![],
+        };
+        let sync = FrontendMessage::Sync.encode().unwrap();
+        let call =
+            break_call_request(&[parse.encode().unwrap(), sync.clone()], &none, b'I').unwrap();
+        assert_eq!(tags(&call.responses), "1Z");
+        assert!(call.executed.is_empty());
+        assert_eq!(call.statements["b"], None);
+        let bind = FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "b".to_string(),
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        };
+        let execute = FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        };
+        let request = [
+            bind.encode().unwrap(),
+            execute.encode().unwrap(),
+            sync.clone(),
+        ];
+        let prepared = call.statements;
+        let call = break_call_request(&request, &prepared, b'I').unwrap();
+        assert_eq!(tags(&call.responses), "2DCZ");
+        assert_eq!(call.executed, [None]);
+        assert!(break_call_request(&request, &none, b'I').is_none());
+
+        // In a failed transaction nothing runs
+        let call = break_call_request(&request, &prepared, b'E').unwrap();
+        assert_eq!(tags(&call.responses), "EZ");
+        assert!(call.executed.is_empty());
+    }
+
+    #[test]
+    fn proxy_commands_are_parsed() {
+        assert_eq!(ProxyCommand::parse("select 1"), None);
+        assert_eq!(
+            ProxyCommand::parse(" pgdproxy continue;"),
+            Some(Ok(ProxyCommand::Resume(Resume::Continue)))
+        );
+        assert_eq!(
+            ProxyCommand::parse("PGDPROXY STEP On"),
+            Some(Ok(ProxyCommand::Step(true)))
+        );
+        assert_eq!(
+            ProxyCommand::parse("pgdproxy rewrite select 1;"),
+            Some(Ok(ProxyCommand::Resume(Resume::Rewrite(
+                "select 1;".to_string()
+            ))))
+        );
+        assert_eq!(
+            ProxyCommand::parse("pgdproxy watch select count(*) from t"),
+            Some(Ok(ProxyCommand::Watch(
+                "select count(*) from t".to_string()
+            )))
+        );
+        assert!(matches!(
+            ProxyCommand::parse("pgdproxy rewrite"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            ProxyCommand::parse("pgdproxy skip it"),
+            Some(Err(_))
+        ));
+        assert!(matches!(ProxyCommand::parse("pgdproxy"), Some(Err(_))));
+    }
+}
@@ -1,22 +1,244 @@
-use std::io::Error;
+use std::{collections::HashMap, io::Error, path::PathBuf, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use postgres_protocol::message::frontend;
 
 use strum::Display;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    time::Instant,
 };
 use tokio_util::codec::Encoder;
 
+use crate::breakpoints::{self, BreakCall, Breakpoints, ProxyCommand};
+use crate::metrics::Metrics;
+use crate::notifications::NotificationBuffer;
+use crate::passthrough::PassthroughTracker;
 use crate::pg_codec::{
-    ForwardingBackendCodec, ForwardingClientCodec, FrameInfo, SslOrStartup, StartupRequest,
+    BackendMessage, ClientCommand, ClientFrame, ForwardingBackendCodec, ForwardingBackendData,
+    ForwardingClientCodec, FrameInfo, FramePart, FrontendMessage, SslOrStartup, StartupMessage,
+    StartupRequest, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_STREAM_THRESHOLD,
 };
-use futures::{SinkExt, StreamExt};
+use crate::query_log::QueryLog;
+use crate::replay::data_row;
+use crate::session::{Pause, Reason, Resume, Session};
+use crate::settings::{self, Setting};
+use crate::tap::{Direction, Origin, Tap, Tapped};
+use futures::{FutureExt, SinkExt, StreamExt};
 use tokio_util::codec::{Decoder, Framed};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+// A Sync message, used to close out an extended query the debug client abandoned
+const SYNC: [u8; 5] = [b'S', 0, 0, 0, 4];
+
+/// Per-session settings handed to the forwarder by the listener
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub debug_binding: Option<String>,
+    /// How many NotificationResponse messages we hold for the app client while a debug client
+    /// is attached. Anything beyond this is dropped.
+    pub notification_buffer_size: usize,
+    /// How long we wait for the rest of a response after the debug client disconnects before
+    /// cancelling the query
+    pub drain_cancel_after: Duration,
+    /// Largest message we accept from the app or debug client
+    pub max_client_message_size: usize,
+    /// Largest message we accept from the target
+    pub max_backend_message_size: usize,
+    /// Target messages larger than this are streamed to the client in chunks
+    pub stream_threshold: usize,
+    /// Copy bytes without decoding them while no debug client is attached
+    pub passthrough: bool,
+    /// Assigned by the listener, used to tell sessions apart in logs
+    pub session_id: u64,
+    /// Where to log the statements this session runs
+    pub query_log: Option<QueryLog>,
+    pub metrics: Option<Metrics>,
+    /// This session's entry in the listener's registry
+    pub session: Option<Arc<Session>>,
+    /// Directory to write a pcap file of this session's traffic to
+    pub pcap_dir: Option<PathBuf>,
+    /// App requests that match are held until someone says to continue
+    pub breakpoints: Breakpoints,
+    /// Errors from the target are held back from the app until someone says to continue
+    pub break_on_error: bool,
+    /// App requests that commit are held until someone says to continue, or to roll back
+    pub break_on_commit: bool,
+    /// SQL run after each app request, in a savepoint that's rolled back, with the results logged
+    pub watches: Vec<String>,
+    /// The app can stop itself with `pgdproxy.break()` or a `/* pgdproxy:break */` comment
+    pub app_breaks: bool,
+    /// Tell the app its debug port and session id in ParameterStatus messages when it connects
+    pub report_settings: bool,
+    /// Startup parameters to set (or, when None, remove) before the target sees them, in order.
+    /// `{session}` in a value is the session id.
+    pub startup_params: Vec<(String, Option<String>)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            debug_binding: None,
+            notification_buffer_size: 1024,
+            drain_cancel_after: Duration::from_secs(5),
+            max_client_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_backend_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            passthrough: true,
+            session_id: 0,
+            query_log: None,
+            metrics: None,
+            session: None,
+            pcap_dir: None,
+            breakpoints: Breakpoints::default(),
+            break_on_error: false,
+            break_on_commit: false,
+            watches: vec![],
+            app_breaks: false,
+            report_settings: false,
+            startup_params: vec![],
+        }
+    }
+}
 
 pub struct ForwarderState {
     client: Framed<TcpStream, ForwardingClientCodec>,
     target: Framed<TcpStream, ForwardingBackendCodec>,
     debug_listener: TcpListener,
+    // Notifications for the app client that arrived while a debug client was attached
+    notifications: NotificationBuffer,
+    // process id and secret key from BackendKeyData, used for cancelling
+    backend_key: Option<(i32, i32)>,
+    options: Options,
+    // Covers everything we do on behalf of the attached debug client, if any
+    debug_span: Option<Span>,
+    tap: Tap,
+    // The app request waiting at a breakpoint, or the error waiting to be seen, if any
+    held: Option<Held>,
+    // A debug client that told the app to continue. It gets control back at the next breakpoint.
+    parked_debug_client: Option<Framed<TcpStream, ForwardingClientCodec>>,
+    // The SQL of the app request in flight, for when it fails
+    request_sql: Option<String>,
+    // Whether we set a savepoint before the app request in flight
+    savepoint: bool,
+    // Statements the app prepared that call pgdproxy.break(), with their labels. We answered
+    // for them, the target has never heard of them.
+    break_statements: HashMap<String, Option<String>>,
+}
+
+enum Held {
+    /// An app request that hit a breakpoint, and hasn't been sent to the target
+    Request {
+        request: Vec<ClientCommand>,
+        pause: Pause,
+    },
+    /// The rest of a failed app request's response, from its ErrorResponse on. If we rolled back
+    /// to the savepoint from before the request, the transaction has to fail again before the
+    /// app hears about it.
+    Error {
+        response: Vec<ForwardingBackendData>,
+        pause: Pause,
+        rolled_back: bool,
+    },
+}
+
+impl Held {
+    fn pause(&self) -> &Pause {
+        match self {
+            Held::Request { pause, .. } | Held::Error { pause, .. } => pause,
+        }
+    }
+}
+
+impl ForwarderState {
+    fn is_stepping(&self) -> bool {
+        self.options
+            .session
+            .as_ref()
+            .is_some_and(|session| session.is_stepping())
+    }
+
+    /// The listener's watch queries, then the session's
+    fn watches(&self) -> Vec<String> {
+        let mut watches = self.options.watches.clone();
+        if let Some(session) = &self.options.session {
+            watches.extend(session.watches());
+        }
+        watches
+    }
+
+    /// Whether app requests are read whole and checked before they're sent
+    fn is_gated(&self) -> bool {
+        !self.options.breakpoints.is_empty()
+            || self.is_stepping()
+            || self.options.break_on_error
+            || self.options.break_on_commit
+            || !self.watches().is_empty()
+            || self.options.app_breaks
+    }
+
+    /// The value of one of the settings only we know
+    fn setting(&self, setting: Setting) -> String {
+        match setting {
+            Setting::DebugPort => self
+                .debug_listener
+                .local_addr()
+                .map(|addr| addr.port().to_string())
+                .unwrap_or_default(),
+            Setting::SessionId => self.options.session_id.to_string(),
+        }
+    }
+
+    /// Why an app request should be held, if it should
+    fn stop(&self, request: &[ClientCommand]) -> Option<Pause> {
+        if self.options.app_breaks {
+            if let Some(pause) = breakpoints::app_break(request, &self.tap, &self.break_statements)
+            {
+                return Some(pause);
+            }
+        }
+        if let Some(pause) = self.options.breakpoints.hit(request, &self.tap) {
+            return Some(pause);
+        }
+        if self.options.break_on_commit {
+            if let Some(pause) = breakpoints::commit(request, &self.tap) {
+                return Some(pause);
+            }
+        }
+        if self.is_stepping() {
+            return breakpoints::step(request, &self.tap);
+        }
+        None
+    }
+}
+
+// Set before each app statement in a transaction when breaking on errors
+const SAVEPOINT: &str = "pgdproxy_before_error";
+// Fails the transaction again, after we rolled back to the savepoint
+const ABORT: &str =
+    "DO $$ BEGIN RAISE EXCEPTION 'pgdproxy: failing the transaction again for the app'; END $$";
+
+/// What one of the proxy's own queries returned
+#[derive(Debug, Default)]
+struct InternalResult {
+    /// In text, as simple queries return them
+    rows: Vec<Vec<Option<String>>>,
+    /// The SQLSTATE and message, if it failed
+    error: Option<(String, String)>,
+}
+
+/// "SQLSTATE: message" for an ErrorResponse
+fn error_summary(data: &ForwardingBackendData) -> String {
+    let fields = data.error_fields();
+    let field = |tag| {
+        fields
+            .iter()
+            .find(|(field, _)| *field == tag)
+            .map(|(_, value)| value.as_str())
+            .unwrap_or_default()
+    };
+    format!("{}: {}", field(b'C'), field(b'M'))
 }
 
 #[derive(Display)]
@@ -24,16 +246,25 @@ pub enum Forwarder {
     Start {
         client: TcpStream,
         target: TcpStream,
-        debug_binding: Option<String>,
+        options: Options,
     },
     Authenticated {
         client: Framed<TcpStream, ForwardingClientCodec>,
         target: Framed<TcpStream, ForwardingBackendCodec>,
-        debug_binding: Option<String>,
+        debug_listener: TcpListener,
+        options: Options,
+        backend_key: Option<(i32, i32)>,
     },
     Listening {
         state: ForwarderState,
     },
+    /// No debug client is attached, so we copy bytes without framing them. We only track message
+    /// boundaries, so we can switch back when a debug client connects.
+    Passthrough {
+        state: ForwarderState,
+        tracker: Box<PassthroughTracker>,
+        pending_debug_client: Option<TcpStream>,
+    },
     ForwardingClient {
         state: ForwarderState,
     },
@@ -52,21 +283,684 @@ pub enum Forwarder {
         state: ForwarderState,
         debug_client: Framed<TcpStream, ForwardingClientCodec>,
     },
+    /// The debug client went away with a request in flight. We discard the target's response
+    /// until ReadyForQuery so the app doesn't receive it. If it takes too long we send a cancel.
+    DebugDraining {
+        state: ForwarderState,
+        cancel_at: Option<Instant>,
+    },
 }
 
 impl Forwarder {
+    /// Where we go once a request is finished. When we can, that's passthrough mode, where bytes
+    /// are copied between the client and target without being decoded.
+    fn idle(mut state: ForwarderState) -> Self {
+        // Nothing will hand a parked debug client control again
+        if !state.is_gated() && state.parked_debug_client.take().is_some() {
+            info!("Letting go of parked debug client");
+        }
+        // A parked debug client is still attached, just waiting for the next breakpoint
+        if state.parked_debug_client.is_none() && state.debug_span.take().is_some() {
+            state.tap.debug_detached();
+        }
+        // Breakpoints and step mode have to see every request, and a held one is only let go
+        // from Listening
+        if state.options.passthrough
+            && !state.tap.is_active()
+            && state.notifications.is_empty()
+            && !state.is_gated()
+            && state.held.is_none()
+        {
+            let tracker = PassthroughTracker::new(&state.options, &state.tap);
+            Self::Passthrough {
+                state,
+                tracker: Box::new(tracker),
+                pending_debug_client: None,
+            }
+        } else {
+            Self::Listening { state }
+        }
+    }
+
+    async fn attach_debug_client(mut state: ForwarderState, mut debug_client: TcpStream) -> Self {
+        let span = match debug_client.peer_addr() {
+            Ok(addr) => info_span!("debug", peer = %addr),
+            Err(_) => info_span!("debug", peer = field::Empty),
+        };
+        let max_message_size = state.options.max_client_message_size;
+        let pause = state.held.as_ref().map(Held::pause);
+        let debug_client = async move {
+            info!("Debug client connected");
+            // A debug client that can't start up shouldn't take the session with it
+            if let Err(e) = Self::fake_startup(&mut debug_client).await {
+                warn!(error = %e, "Error starting up debug client");
+                return None;
+            }
+            let mut debug_client =
+                ForwardingClientCodec::new(max_message_size).framed(debug_client);
+            let mut result = Self::fake_authenticate(&mut debug_client).await;
+            if let (Ok(_), Some(pause)) = (&result, pause) {
+                result = Self::announce_pause(&mut debug_client, pause).await;
+            }
+            match result {
+                Ok(_) => Some(debug_client),
+                Err(e) => {
+                    warn!(error = %e, "Error authenticating debug client");
+                    None
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        let Some(debug_client) = debug_client else {
+            return Self::Listening { state };
+        };
+        if state.parked_debug_client.take().is_some() {
+            info!("Replacing parked debug client");
+            state.tap.debug_detached();
+        }
+        state.debug_span = Some(span);
+        state.tap.debug_attached();
+        Self::DebugMode {
+            state,
+            debug_client,
+        }
+    }
+
+    /// Resolves when something about the session changes, never if it isn't registered
+    async fn session_changed(session: Option<&Arc<Session>>) {
+        match session {
+            Some(session) => session.changed().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Resolves when the app is let go from a breakpoint, never if the session isn't registered
+    async fn session_resumed(session: Option<&Arc<Session>>) -> Resume {
+        match session {
+            Some(session) => session.resumed().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Reads the rest of an app request. A simple Query is a request on its own, an extended
+    /// query runs up to its Sync or Flush. Anything else, like CopyData, is passed on as it is.
+    async fn read_request(
+        client: &mut Framed<TcpStream, ForwardingClientCodec>,
+        first: ClientCommand,
+    ) -> Result<Vec<ClientCommand>, Error> {
+        let extended = b"PBDEC".contains(&first[0]);
+        let mut request = vec![first];
+        while extended && !matches!(request.last().map(|m| m[0]), Some(b'S' | b'H')) {
+            match client.next().await {
+                Some(data) => request.push(data?),
+                None => {
+                    debug!("Client disconnected");
+                    return Err(Error::other("Client disconnected"));
+                }
+            }
+        }
+        Ok(request)
+    }
+
+    /// Sends an app request to the target, or answers it ourselves if all it does is call
+    /// `pgdproxy.break()`
+    async fn send_request(
+        state: &mut ForwarderState,
+        request: Vec<ClientCommand>,
+    ) -> Result<(), Error> {
+        // Our answer can only go in the right place if the app has nothing else on the wire.
+        // Mid extended query the call goes to the target, which refuses it.
+        if state.options.app_breaks && state.tap.is_app_idle() {
+            let status = state.tap.transaction_status();
+            if let Some(call) =
+                breakpoints::break_call_request(&request, &state.break_statements, status)
+            {
+                return Self::answer_break_call(state, request, call).await;
+            }
+        }
+        state.request_sql = breakpoints::describe(&request, &state.tap);
+        if state.options.break_on_error {
+            // Outside a transaction there's nothing to go back to, and in a failed one we can't.
+            // The savepoint's reply can only be told apart from the app's if the app is idle, and
+            // then the status is the one its last request left.
+            state.savepoint = state.tap.is_app_idle()
+                && state.tap.transaction_status() == b'T'
+                && Self::internal_query(state, &format!("SAVEPOINT {SAVEPOINT}"))
+                    .await?
+                    .error
+                    .is_none();
+        }
+        for data in request {
+            // The target gets a statement of this name, so it's not ours any more
+            if !state.break_statements.is_empty() {
+                if let Ok(FrontendMessage::Parse { name, .. }) = data.message() {
+                    state.break_statements.remove(&name);
+                }
+            }
+            state.tap.client_message(Origin::App, &data);
+            state.target.feed(data).await?;
+        }
+        state.target.flush().await
+    }
+
+    /// Where we go once an app request is sent. Its response is forwarded before the next request
+    /// is read, so nothing else can get between them on the target. A request that ends in a
+    /// Flush gets no ReadyForQuery to wait for, its response is forwarded as it comes.
+    fn request_sent(state: ForwarderState) -> Self {
+        if state.tap.outstanding() > 0 {
+            Self::ForwardingServer { state }
+        } else {
+            Self::Listening { state }
+        }
+    }
+
+    /// Answers a `SHOW` of one of our settings, see `Setting::shown`
+    async fn answer_show(state: &mut ForwarderState, data: ClientCommand) -> Result<(), Error> {
+        let Some(setting) = Setting::shown(&data) else {
+            return Ok(());
+        };
+        // Our answer goes after the responses to whatever the app sent before it
+        Self::finish_responses(state).await?;
+        debug!(setting = setting.name(), "Answering SHOW");
+        state.tap.client_message(Origin::App, &data);
+        let status = state.tap.transaction_status();
+        for message in setting.show(&state.setting(setting), status) {
+            let data = message.encode();
+            state.tap.backend_message(Origin::App, &data);
+            state.client.feed(data).await?;
+        }
+        state.client.flush().await
+    }
+
+    /// Forwards the responses to the app's pipelined requests, until it has had them all
+    async fn finish_responses(state: &mut ForwarderState) -> Result<(), Error> {
+        while state.tap.outstanding() > 0 {
+            let data = match state.target.next().await {
+                Some(data) => data?,
+                None => {
+                    info!("Target disconnected");
+                    return Err(Error::other("Target disconnected"));
+                }
+            };
+            let done = data.done();
+            state.tap.backend_message(Origin::App, &data);
+            state.client.feed(data).await?;
+            if done {
+                state.client.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn answer_break_call(
+        state: &mut ForwarderState,
+        request: Vec<ClientCommand>,
+        call: BreakCall,
+    ) -> Result<(), Error> {
+        debug!(labels = ?call.executed, "Answering pgdproxy.break()");
+        for data in &request {
+            state.tap.client_message(Origin::App, data);
+        }
+        for response in call.responses {
+            let data = response.encode();
+            state.tap.backend_message(Origin::App, &data);
+            state.client.feed(data).await?;
+        }
+        state.break_statements = call.statements;
+        state.client.flush().await
+    }
+
+    /// Runs a query of our own on the target, out of the app's sight. Returns the error message
+    /// if it failed. The app mustn't have a request on the wire, its response would be taken for
+    /// ours.
+    async fn internal_query(
+        state: &mut ForwarderState,
+        sql: &str,
+    ) -> Result<InternalResult, Error> {
+        debug!(sql, "Running internal query");
+        let query = FrontendMessage::Query {
+            query: sql.to_string(),
+        };
+        state.target.send(query.encode()?).await?;
+        let mut result = InternalResult::default();
+        loop {
+            match state.target.next().await {
+                Some(Ok(data)) if data.is_notification() => state.notifications.push(data),
+                Some(Ok(data)) => {
+                    if let Some(sqlstate) = data.sqlstate() {
+                        warn!(sql, error = error_summary(&data), "Internal query failed");
+                        let message = data
+                            .error_fields()
+                            .into_iter()
+                            .find(|(field, _)| *field == b'M');
+                        result.error =
+                            Some((sqlstate, message.map(|(_, m)| m).unwrap_or_default()));
+                    }
+                    if data.command() == Some(b'D') {
+                        result.rows.push(data_row(&data.as_bytes()[5..]));
+                    }
+                    if let Some(status) = data.transaction_status() {
+                        state.tap.set_transaction_status(status);
+                        return Ok(result);
+                    }
+                }
+                Some(Err(e)) => return Err(e),
+                None => {
+                    info!("Target disconnected");
+                    return Err(Error::other("Target disconnected"));
+                }
+            }
+        }
+    }
+
+    /// Where we go once the app has its response
+    async fn app_request_done(mut state: ForwarderState) -> Result<Self, Error> {
+        Self::run_watches(&mut state).await?;
+        Ok(Self::idle(state))
+    }
+
+    /// Runs the watch queries and logs what they return. Each runs in a savepoint, or a
+    /// transaction of its own, that's rolled back, so they can't change what the app sees.
+    async fn run_watches(state: &mut ForwarderState) -> Result<(), Error> {
+        let watches = state.watches();
+        let after = state.request_sql.take();
+        if watches.is_empty() {
+            return Ok(());
+        }
+        // Mid extended query, say after a Flush, the app's next response could be taken for ours
+        if !state.tap.is_app_idle() {
+            debug!("App request in flight, skipping watches");
+            return Ok(());
+        }
+        let (begin, end) = match state.tap.transaction_status() {
+            b'T' => (
+                "SAVEPOINT pgdproxy_watch",
+                "ROLLBACK TO SAVEPOINT pgdproxy_watch; RELEASE SAVEPOINT pgdproxy_watch",
+            ),
+            b'I' => ("BEGIN", "ROLLBACK"),
+            _ => {
+                debug!("Transaction failed, skipping watches");
+                return Ok(());
+            }
+        };
+        for sql in &watches {
+            Self::internal_query(state, begin).await?;
+            let started = Instant::now();
+            let result = Self::internal_query(state, sql).await?;
+            let duration = started.elapsed();
+            Self::internal_query(state, end).await?;
+            match &result.error {
+                None => info!(watch = sql, after, rows = ?result.rows, "Watch"),
+                Some((sqlstate, message)) => {
+                    info!(watch = sql, after, sqlstate, message, "Watch failed")
+                }
+            }
+            let outcome = match &result.error {
+                None => Ok(result.rows.as_slice()),
+                Some((sqlstate, message)) => Err((sqlstate.as_str(), message.as_str())),
+            };
+            state.tap.watch(sql, after.as_deref(), outcome, duration);
+        }
+        Ok(())
+    }
+
+    /// Forwards the target's response to an app request. If the request fails, the error and the
+    /// rest of the response are held back until someone says to continue.
+    async fn forward_response(
+        mut state: ForwarderState,
+        first: ForwardingBackendData,
+    ) -> Result<Self, Error> {
+        let mut message = Some(first);
+        loop {
+            let data = match message.take() {
+                Some(data) => data,
+                None => {
+                    let next = match state.target.next().now_or_never() {
+                        Some(next) => next,
+                        None => {
+                            state.client.flush().await?;
+                            state.target.next().await
+                        }
+                    };
+                    match next {
+                        Some(data) => data?,
+                        None => {
+                            info!("Target disconnected");
+                            return Err(Error::other("Target disconnected"));
+                        }
+                    }
+                }
+            };
+            // A failure in a transaction that had already failed tells us nothing new
+            if data.part() == FramePart::Whole
+                && data.sqlstate().is_some_and(|code| code != "25P02")
+            {
+                state.client.flush().await?;
+                return Self::hold_error(state, data).await;
+            }
+            let done = data.done();
+            state.tap.backend_message(Origin::App, &data);
+            state.client.feed(data).await?;
+            if done {
+                state.client.flush().await?;
+                return Self::app_request_done(state).await;
+            }
+        }
+    }
+
+    /// Reads the rest of a failed request's response and holds it. If we set a savepoint before
+    /// the request, we roll back to it so the debug client can query the transaction as it was.
+    async fn hold_error(
+        mut state: ForwarderState,
+        error: ForwardingBackendData,
+    ) -> Result<Self, Error> {
+        let summary = error_summary(&error);
+        let mut response = vec![];
+        let mut data = error;
+        loop {
+            state.tap.backend_message(Origin::App, &data);
+            let done = data.done();
+            response.push(data);
+            if done {
+                break;
+            }
+            data = match state.target.next().await {
+                Some(data) => data?,
+                None => {
+                    info!("Target disconnected");
+                    return Err(Error::other("Target disconnected"));
+                }
+            };
+        }
+        let rolled_back = std::mem::take(&mut state.savepoint)
+            && state.tap.is_app_idle()
+            && state.tap.transaction_status() == b'E'
+            && Self::internal_query(&mut state, &format!("ROLLBACK TO SAVEPOINT {SAVEPOINT}"))
+                .await?
+                .error
+                .is_none();
+        let pause = Pause {
+            reason: Reason::Error { error: summary },
+            sql: state.request_sql.take().unwrap_or_default(),
+            params: vec![],
+        };
+        Ok(Self::pause(
+            state,
+            Held::Error {
+                response,
+                pause,
+                rolled_back,
+            },
+        )
+        .await)
+    }
+
+    /// Holds the app's request, or its error, until a debug client or the admin interface says
+    /// to continue
+    async fn pause(mut state: ForwarderState, held: Held) -> Self {
+        let pause = held.pause();
+        info!(reason = ?pause.reason, sql = pause.sql, "Paused app request");
+        if let Some(session) = &state.options.session {
+            session.pause(pause.clone());
+        }
+        let parked = state.parked_debug_client.take();
+        state.held = Some(held);
+        // A debug client that's still around picks up where it left off
+        match parked {
+            Some(mut debug_client) => {
+                let pause = state.held.as_ref().unwrap().pause();
+                match Self::announce_pause(&mut debug_client, pause).await {
+                    Ok(()) => Self::DebugMode {
+                        state,
+                        debug_client,
+                    },
+                    Err(e) => {
+                        info!(error = %e, "Parked debug client is gone");
+                        Self::idle(state)
+                    }
+                }
+            }
+            None => Self::Listening { state },
+        }
+    }
+
+    async fn announce_pause(
+        debug_client: &mut Framed<TcpStream, ForwardingClientCodec>,
+        pause: &Pause,
+    ) -> Result<(), Error> {
+        let sql = &pause.sql;
+        let mut message = match &pause.reason {
+            Reason::Breakpoint { breakpoint } => {
+                format!("pgdproxy: app paused at breakpoint /{breakpoint}/ before: {sql}")
+            }
+            Reason::Step => format!("pgdproxy: app paused, stepping, before: {sql}"),
+            Reason::Commit { .. } => format!("pgdproxy: app paused before it commits: {sql}"),
+            Reason::Break { label: Some(label) } => {
+                format!("pgdproxy: app paused itself at '{label}' before: {sql}")
+            }
+            Reason::Break { label: None } => format!("pgdproxy: app paused itself before: {sql}"),
+            Reason::Error { error } => {
+                format!(
+                    "pgdproxy: app request failed with {error}, the app hasn't seen it yet: {sql}"
+                )
+            }
+        };
+        if !pause.params.is_empty() {
+            let params: Vec<&str> = pause
+                .params
+                .iter()
+                .map(|param| param.as_deref().unwrap_or("NULL"))
+                .collect();
+            message.push_str(&format!(" [{}]", params.join(", ")));
+        }
+        debug_client
+            .send(BackendMessage::notice(&message).encode())
+            .await
+    }
+
+    /// Sends the held request on, or what's to run in its place, or lets the app see its error.
+    /// A debug client that asked for this waits, parked, for the next breakpoint.
+    async fn resume(
+        mut state: ForwarderState,
+        debug_client: Option<Framed<TcpStream, ForwardingClientCodec>>,
+        resume: Resume,
+    ) -> Result<Self, Error> {
+        if let Some(session) = &state.options.session {
+            session.resume(resume.clone());
+        }
+        state.parked_debug_client = debug_client;
+        match state.held.take() {
+            Some(Held::Request { request, pause }) => {
+                info!(reason = ?pause.reason, resume = ?resume, "Continuing app request");
+                let request = match breakpoints::apply(&request, &resume) {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        warn!(error = e, "Can't change held request, sending it as it is");
+                        request
+                    }
+                };
+                Self::send_request(&mut state, request).await?;
+                return Ok(Self::request_sent(state));
+            }
+            Some(Held::Error {
+                response,
+                rolled_back,
+                ..
+            }) => {
+                info!("Passing on app request's error");
+                // The app is told its transaction failed, so it has to have
+                if rolled_back {
+                    Self::internal_query(&mut state, ABORT).await?;
+                }
+                for data in response {
+                    state.client.feed(data).await?;
+                }
+                state.client.flush().await?;
+            }
+            None => {}
+        }
+        Ok(Self::Listening { state })
+    }
+
+    /// Answers a command meant for the proxy rather than the target
+    async fn proxy_command(
+        state: ForwarderState,
+        mut debug_client: Framed<TcpStream, ForwardingClientCodec>,
+        command: Result<ProxyCommand, String>,
+    ) -> Result<Self, Error> {
+        let status = state.tap.transaction_status();
+        let reply = match &command {
+            Ok(ProxyCommand::Resume(resume)) => match &state.held {
+                // A rewrite that can't be done is refused now, while there's someone to tell
+                Some(Held::Request { pause, .. })
+                    if *resume == Resume::Rollback
+                        && !matches!(pause.reason, Reason::Commit { .. }) =>
+                {
+                    Err("pgdproxy: the app isn't committing".to_string())
+                }
+                Some(Held::Request { request, .. }) => {
+                    breakpoints::apply(request, resume).map(|_| ())
+                }
+                Some(Held::Error { .. }) if *resume != Resume::Continue => {
+                    Err("pgdproxy: the app's request already ran, it can only continue".to_string())
+                }
+                Some(Held::Error { .. }) => Ok(()),
+                None => Err("pgdproxy: the app isn't paused".to_string()),
+            },
+            Ok(ProxyCommand::Step(on)) => match &state.options.session {
+                Some(session) => {
+                    info!(on, "Step mode set from debug client");
+                    session.set_stepping(*on);
+                    Ok(())
+                }
+                None => Err("pgdproxy: step mode needs a registered session".to_string()),
+            },
+            Ok(ProxyCommand::Watch(sql)) => match &state.options.session {
+                Some(session) => {
+                    info!(sql, "Watch added from debug client");
+                    session.watch(sql.clone());
+                    Ok(())
+                }
+                None => Err("pgdproxy: watches need a registered session".to_string()),
+            },
+            Ok(ProxyCommand::Unwatch) => match &state.options.session {
+                Some(session) => {
+                    let watches = session.unwatch();
+                    info!(?watches, "Watches removed from debug client");
+                    Ok(())
+                }
+                None => Err("pgdproxy: watches need a registered session".to_string()),
+            },
+            Err(e) => Err(e.clone()),
+        };
+        let message = match (&reply, &command) {
+            (Ok(()), Ok(command)) => BackendMessage::CommandComplete {
+                tag: command.tag().to_string(),
+            },
+            (Err(message), _) | (_, Err(message)) => BackendMessage::error("42601", message),
+        };
+        debug_client.feed(message.encode()).await?;
+        debug_client
+            .send(BackendMessage::ReadyForQuery { status }.encode())
+            .await?;
+        match (reply, command) {
+            (Ok(()), Ok(ProxyCommand::Resume(resume))) => {
+                Self::resume(state, Some(debug_client), resume).await
+            }
+            _ => Ok(Self::DebugMode {
+                state,
+                debug_client,
+            }),
+        }
+    }
+
+    /// For when the debug client goes away before the end of its response. The target could be
+    /// waiting on the rest of an extended query, a Sync makes it respond (or error out) so we can
+    /// drain up to ReadyForQuery.
+    async fn sync_and_drain(mut state: ForwarderState) -> Result<Self, Error> {
+        if state.tap.is_debug_unsynced() {
+            state.target.send(BytesMut::from(&SYNC[..])).await?;
+        }
+        Ok(Self::draining(state))
+    }
+
+    fn draining(state: ForwarderState) -> Self {
+        let cancel_at = Some(Instant::now() + state.options.drain_cancel_after);
+        Self::DebugDraining { state, cancel_at }
+    }
+
+    /// If `e` is a protocol violation (a malformed or oversized message from either side), tell
+    /// the client with a 08P01 error before we hang up on it. Hands back `e` for the caller.
+    async fn reject(client: &mut Framed<TcpStream, ForwardingClientCodec>, e: Error) -> Error {
+        if e.kind() == std::io::ErrorKind::InvalidData {
+            warn!(error = %e, "Protocol violation");
+            let message = format!("pgdproxy: protocol violation: {}", e);
+            let _ = client
+                .send(BackendMessage::fatal("08P01", &message).encode())
+                .await;
+        }
+        e
+    }
+
+    /// Same as `reject`, but before the client is framed
+    async fn reject_startup(client: &mut TcpStream, e: Error) -> Error {
+        if e.kind() == std::io::ErrorKind::InvalidData {
+            warn!(error = %e, "Protocol violation during startup");
+            let message = format!("pgdproxy: protocol violation: {}", e);
+            let error = BackendMessage::fatal("08P01", &message).encode();
+            let _ = client.write_all(error.as_bytes()).await;
+        }
+        e
+    }
+
+    /// Asks postgres to cancel whatever the target connection is running. This goes over a new
+    /// connection, like any other CancelRequest.
+    async fn cancel_request(state: &ForwarderState) {
+        let Some((process_id, secret_key)) = state.backend_key else {
+            warn!("No BackendKeyData for target, can't cancel");
+            return;
+        };
+        let result = async {
+            let addr = state.target.get_ref().peer_addr()?;
+            let mut conn = TcpStream::connect(addr).await?;
+            let mut buf = BytesMut::new();
+            frontend::cancel_request(process_id, secret_key, &mut buf);
+            conn.write_all(&buf).await
+        }
+        .await;
+        match result {
+            Ok(_) => info!(backend_pid = process_id, "Sent cancel request"),
+            Err(e) => warn!(error = %e, "Error sending cancel request"),
+        }
+    }
+
     pub async fn start(
         client: TcpStream,
         target: TcpStream,
-        debug_binding: Option<String>,
+        options: Options,
     ) -> Result<Self, Error> {
         let mut state = Self::Start {
             client,
             target,
-            debug_binding,
+            options,
         };
         loop {
-            state = state.run().await?;
+            state = match state.debug_span() {
+                Some(span) => state.run().instrument(span).await?,
+                None => state.run().await?,
+            };
+        }
+    }
+
+    /// The span of the attached debug client, for the states that act on its behalf
+    fn debug_span(&self) -> Option<Span> {
+        match self {
+            Self::DebugMode { state, .. }
+            | Self::DebugForwardingClient { state, .. }
+            | Self::DebugForwardingServer { state, .. }
+            | Self::DebugDraining { state, .. } => state.debug_span.clone(),
+            _ => None,
         }
     }
 
@@ -75,67 +969,126 @@ impl Forwarder {
             Forwarder::Start {
                 mut client,
                 mut target,
-                debug_binding,
+                options,
             } => {
-                Self::startup(&mut client, &mut target).await?;
-                let mut client = ForwardingClientCodec.framed(client);
-                let mut target = ForwardingBackendCodec.framed(target);
+                let label = Self::startup(&mut client, &mut target, &options).await?;
+                if let Some(label) = label {
+                    Span::current().record("label", label.as_str());
+                    info!(label, "Session labelled");
+                    if let Some(session) = &options.session {
+                        session.set_label(label);
+                    }
+                }
+                let mut client =
+                    ForwardingClientCodec::new(options.max_client_message_size).framed(client);
+                let mut target = ForwardingBackendCodec::new(options.max_backend_message_size)
+                    .with_stream_threshold(options.stream_threshold)
+                    .framed(target);
+                // The port's bound first, so the app can be told it as it connects
+                let debug_listener = Self::bind_debug_listener(&options).await?;
+                let settings = if options.report_settings {
+                    let debug_port = debug_listener.local_addr()?.port();
+                    vec![
+                        (Setting::DebugPort, debug_port.to_string()),
+                        (Setting::SessionId, options.session_id.to_string()),
+                    ]
+                } else {
+                    vec![]
+                };
                 // Do authentication
-                Self::authenticate(&mut client, &mut target).await?;
+                let backend_key = match Self::authenticate(&mut client, &mut target, settings).await
+                {
+                    Ok(backend_key) => backend_key,
+                    Err(e) => Err(Self::reject(&mut client, e).await)?,
+                };
+                if let Some((process_id, _)) = backend_key {
+                    Span::current().record("backend_pid", process_id);
+                }
                 Self::Authenticated {
                     client,
                     target,
-                    debug_binding,
+                    debug_listener,
+                    options,
+                    backend_key,
                 }
             }
             Forwarder::Authenticated {
                 client,
                 target,
-                debug_binding,
+                debug_listener,
+                options,
+                backend_key,
             } => {
-                // TODO: If a Debug Binding is specified, we will try to spawn a debug listener on
-                // the specified port. If not available, we'll increment until we find an available one.
-                let binding = debug_binding
-                    .as_ref()
-                    .and_then(|s| s.split_once(":"))
-                    .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)));
-
-                let debug_listener = if let Some((host, port_base)) = binding {
-                    let mut listener = None;
-                    for port in port_base..(port_base + 10) {
-                        match TcpListener::bind(format!("{host}:{port}")).await {
-                            Ok(new_listener) => {
-                                listener = Some(new_listener);
-                                break;
-                            }
-                            Err(_) => {
-                                continue;
-                            }
-                        }
-                    }
-                    listener.unwrap_or(TcpListener::bind("localhost:0").await.unwrap())
-                } else {
-                    TcpListener::bind("localhost:0").await.unwrap()
-                };
-
-                println!(
-                    "Listening for debug on port {}",
-                    debug_listener.local_addr().unwrap().port()
-                );
-                Self::Listening {
-                    state: ForwarderState {
-                        client,
-                        target,
-                        debug_listener,
-                    },
+                let debug_port = debug_listener.local_addr().unwrap().port();
+                Span::current().record("debug_port", debug_port);
+                if let Some(session) = &options.session {
+                    session.set_debug_port(debug_port);
                 }
+                info!(debug_port, "Listening for debug");
+                Self::idle(ForwarderState {
+                    client,
+                    target,
+                    debug_listener,
+                    notifications: NotificationBuffer::new(
+                        options.notification_buffer_size,
+                        options.metrics.clone(),
+                    ),
+                    backend_key,
+                    tap: Tap::new(&options).await,
+                    options,
+                    debug_span: None,
+                    held: None,
+                    parked_debug_client: None,
+                    request_sql: None,
+                    savepoint: false,
+                    break_statements: HashMap::new(),
+                })
             }
             Forwarder::Listening { mut state } => {
+                // Anything the app missed while a debug client was attached goes out first
+                if !state.notifications.is_empty() {
+                    let count = state.notifications.flush(&mut state.client).await?;
+                    info!(
+                        count,
+                        buffered = state.notifications.buffered(),
+                        dropped = state.notifications.dropped(),
+                        "Flushed notifications to client"
+                    );
+                }
                 tokio::select! {
-                    message = state.client.next() => {
+                    message = state.client.next(), if state.held.is_none() => {
                         match message {
+                            // Mid extended query we can't tell where our answer would go, so
+                            // the target gets it
+                            Some(Ok(data)) if Setting::shown(&data).is_some() && !state.tap.is_unsynced() => {
+                                Self::answer_show(&mut state, data).await?;
+                                Self::Listening { state }
+                            }
+                            Some(Ok(data)) if state.is_gated() => {
+                                let request = match Self::read_request(&mut state.client, data).await {
+                                    Ok(request) => request,
+                                    Err(e) => Err(Self::reject(&mut state.client, e).await)?,
+                                };
+                                // Requests are read one at a time here, so the app only has one
+                                // on the wire if it's mid extended query, after a Flush
+                                let pause = if state.tap.is_app_idle() {
+                                    state.stop(&request)
+                                } else {
+                                    None
+                                };
+                                match pause {
+                                    Some(pause) => Self::pause(state, Held::Request { request, pause }).await,
+                                    None => {
+                                        Self::send_request(&mut state, request).await?;
+                                        Self::request_sent(state)
+                                    }
+                                }
+                            }
                             Some(Ok(data)) => {
-                                let (done, _) = Self::forward(&mut state.client, &mut state.target, Some(data)).await?;
+                                let (done, _) = match Self::forward(&mut state.client, &mut state.target, &mut state.tap, Origin::App, Some(data)).await {
+                                    Ok(result) => result,
+                                    Err(e) => Err(Self::reject(&mut state.client, e).await)?,
+                                };
                                 if done {
                                     Self::Listening { state }
                                 } else {
@@ -143,64 +1096,206 @@ impl Forwarder {
                                 }
                             }
                             Some(Err(e)) => {
-                                Err(e)?
+                                Err(Self::reject(&mut state.client, e).await)?
                             }
                             None => {
-                                println!("Client disconnected");
-                                Err(Error::new(std::io::ErrorKind::Other, "Client disconnected"))?
+                                debug!("Client disconnected");
+                                Err(Error::other("Client disconnected"))?
                             }
                         }
                     },
                     message = state.target.next() => {
                         match message {
+                            Some(Ok(data)) if data.is_notification() => {
+                                // Asynchronous, so there is no response to wait for
+                                state.client.send(data).await?;
+                                Self::Listening { state }
+                            }
+                            Some(Ok(data)) if state.options.break_on_error => {
+                                Self::forward_response(state, data).await?
+                            }
                             Some(Ok(data)) => {
-                                let (done, _) = Self::forward(&mut state.target, &mut state.client, Some(data)).await?;
+                                let (done, _) = match Self::forward(&mut state.target, &mut state.client, &mut state.tap, Origin::App, Some(data)).await {
+                                    Ok(result) => result,
+                                    Err(e) => Err(Self::reject(&mut state.client, e).await)?,
+                                };
                                 if done {
-                                    Self::Listening { state }
+                                    Self::app_request_done(state).await?
                                 } else {
                                     Self::ForwardingServer { state }
                                 }
                             }
                             Some(Err(e)) => {
-                                Err(e)?
+                                Err(Self::reject(&mut state.client, e).await)?
                             }
                             None => {
-                                println!("Target disconnected");
-                                Err(Error::new(std::io::ErrorKind::Other, "Target disconnected"))?
+                                info!("Target disconnected");
+                                Err(Error::other("Target disconnected"))?
                             }
                         }
                     }
-                    data = state.debug_listener.accept() => {
+                    // A debug client would get the app's response, it waits until there is none
+                    data = state.debug_listener.accept(), if state.tap.is_app_idle() => {
                         match data {
-                            Ok((mut debug_client, _)) => {
-                                Self::fake_startup(&mut debug_client).await?;
-                                let mut debug_client = ForwardingClientCodec.framed(debug_client);
-                                Self::fake_authenticate(&mut debug_client).await?;
-                                Self::DebugMode {
-                                    state,
-                                    debug_client,
+                            Ok((debug_client, _)) => Self::attach_debug_client(state, debug_client).await,
+                            Err(e) => {
+                                warn!(error = %e, "Error accepting debug client");
+                                Err(e)?
+                            }
+                        }
+                    }
+                    resume = Self::session_resumed(state.options.session.as_ref()), if state.held.is_some() => {
+                        Self::resume(state, None, resume).await?
+                    }
+                }
+            }
+            Forwarder::Passthrough {
+                mut state,
+                mut tracker,
+                mut pending_debug_client,
+            } => {
+                // What was read, but not framed, before we got here goes first
+                if !state.client.read_buffer().is_empty() {
+                    let buffered = state.client.read_buffer_mut().split();
+                    if let Err(e) = tracker.scan_client(&buffered) {
+                        Err(Self::reject(&mut state.client, e).await)?
+                    }
+                    state
+                        .tap
+                        .passthrough(Direction::In, buffered.len(), &mut tracker);
+                    state.target.get_mut().write_all(&buffered).await?;
+                }
+                if !state.target.read_buffer().is_empty() {
+                    let buffered = state.target.read_buffer_mut().split();
+                    if let Err(e) = tracker.scan_target(&buffered) {
+                        Err(Self::reject(&mut state.client, e).await)?
+                    }
+                    state
+                        .tap
+                        .passthrough(Direction::Out, buffered.len(), &mut tracker);
+                    state.client.get_mut().write_all(&buffered).await?;
+                }
+                tokio::select! {
+                    n = state.client.get_mut().read(&mut tracker.client_buf) => {
+                        let n = n?;
+                        if n == 0 {
+                            debug!("Client disconnected");
+                            Err(Error::other("Client disconnected"))?
+                        }
+                        // A SHOW of ours sent on its own, between requests, is answered here. One
+                        // that's split over reads, or sent with something else, goes to the target.
+                        match Setting::shown(&tracker.client_buf[..n]) {
+                            Some(setting) if tracker.is_idle() => {
+                                let status = tracker
+                                    .transaction_status()
+                                    .unwrap_or(state.tap.transaction_status());
+                                debug!(setting = setting.name(), "Answering SHOW");
+                                for message in setting.show(&state.setting(setting), status) {
+                                    state.client.get_mut().write_all(message.encode().as_bytes()).await?;
                                 }
                             }
+                            _ => {
+                                if let Err(e) = tracker.client_bytes(n) {
+                                    Err(Self::reject(&mut state.client, e).await)?
+                                }
+                                state.tap.passthrough(Direction::In, n, &mut tracker);
+                                state.target.get_mut().write_all(&tracker.client_buf[..n]).await?;
+                            }
+                        }
+                    }
+                    n = state.target.get_mut().read(&mut tracker.target_buf) => {
+                        let n = n?;
+                        if n == 0 {
+                            info!("Target disconnected");
+                            Err(Error::other("Target disconnected"))?
+                        }
+                        if let Err(e) = tracker.target_bytes(n) {
+                            Err(Self::reject(&mut state.client, e).await)?
+                        }
+                        state.tap.passthrough(Direction::Out, n, &mut tracker);
+                        state.client.get_mut().write_all(&tracker.target_buf[..n]).await?;
+                    }
+                    data = state.debug_listener.accept(), if pending_debug_client.is_none() => {
+                        match data {
+                            Ok((debug_client, _)) => pending_debug_client = Some(debug_client),
                             Err(e) => {
-                                println!("Error accepting debug client: {}", e);
+                                warn!(error = %e, "Error accepting debug client");
                                 Err(e)?
                             }
                         }
                     }
+                    _ = Self::session_changed(state.options.session.as_ref()) => {}
+                }
+                // Framing only picks up cleanly between requests, so a debug client that connects
+                // mid-request waits for the app's request to finish
+                match pending_debug_client {
+                    Some(debug_client) if tracker.is_idle() => {
+                        debug!(
+                            transaction_status = ?tracker.transaction_status().map(char::from),
+                            "Leaving passthrough for debug client"
+                        );
+                        state.tap.passthrough_ended(tracker.transaction_status());
+                        Self::attach_debug_client(state, debug_client).await
+                    }
+                    // Something started watching the session, e.g. it's being recorded now, or
+                    // step mode was turned on
+                    None if tracker.is_idle() && (state.tap.is_active() || state.is_gated()) => {
+                        debug!("Leaving passthrough");
+                        state.tap.passthrough_ended(tracker.transaction_status());
+                        Self::Listening { state }
+                    }
+                    pending_debug_client => Self::Passthrough {
+                        state,
+                        tracker,
+                        pending_debug_client,
+                    },
                 }
             }
             Forwarder::ForwardingClient { mut state } => {
-                let (done, _) = Self::forward(&mut state.client, &mut state.target, None).await?;
+                let (done, _) = match Self::forward(
+                    &mut state.client,
+                    &mut state.target,
+                    &mut state.tap,
+                    Origin::App,
+                    None,
+                )
+                .await
+                {
+                    Ok(result) => result,
+                    Err(e) => Err(Self::reject(&mut state.client, e).await)?,
+                };
                 if done {
                     Self::ForwardingServer { state }
                 } else {
                     Self::ForwardingClient { state }
                 }
             }
+            // Errors are held, so each message has to be looked at
+            Forwarder::ForwardingServer { mut state } if state.options.break_on_error => {
+                match state.target.next().await {
+                    Some(Ok(data)) => Self::forward_response(state, data).await?,
+                    Some(Err(e)) => Err(Self::reject(&mut state.client, e).await)?,
+                    None => {
+                        info!("Target disconnected");
+                        Err(Error::other("Target disconnected"))?
+                    }
+                }
+            }
             Forwarder::ForwardingServer { mut state } => {
-                let (done, _) = Self::forward(&mut state.target, &mut state.client, None).await?;
+                let (done, _) = match Self::forward(
+                    &mut state.target,
+                    &mut state.client,
+                    &mut state.tap,
+                    Origin::App,
+                    None,
+                )
+                .await
+                {
+                    Ok(result) => result,
+                    Err(e) => Err(Self::reject(&mut state.client, e).await)?,
+                };
                 if done {
-                    Self::Listening { state }
+                    Self::app_request_done(state).await?
                 } else {
                     Self::ForwardingServer { state }
                 }
@@ -213,10 +1308,18 @@ impl Forwarder {
                     message = debug_client.next() => {
                         match message {
                             Some(Ok(data)) => {
+                                let command = match data.message() {
+                                    Ok(FrontendMessage::Query { query }) => ProxyCommand::parse(&query),
+                                    _ => None,
+                                };
                                 if data[0] == 88 {
-                                    Self::Listening { state }
+                                    Self::idle(state)
+                                } else if let Some(command) = command {
+                                    Self::proxy_command(state, debug_client, command).await?
                                 } else {
-                                    let (done, _) = Self::forward(&mut debug_client, &mut state.target, Some(data)).await?;
+                                    let done = data.done();
+                                    state.tap.client_message(Origin::Debug, &data);
+                                    state.target.send(data).await?;
                                     if done {
                                         Self::DebugForwardingServer { state, debug_client }
                                     } else {
@@ -225,19 +1328,25 @@ impl Forwarder {
                                 }
                             }
                             Some(Err(e)) => {
-                                println!("Error reading from debug client: {:?}", e);
-                                Self::Listening { state }
+                                warn!(error = %e, "Error reading from debug client");
+                                Self::reject(&mut debug_client, e).await;
+                                Self::idle(state)
                             }
                             None => {
-                                println!("Debug client disconnected");
-                                Self::Listening { state }
+                                info!("Debug client disconnected");
+                                Self::idle(state)
                             }
                         }
                     }
                     message = state.target.next() => {
                         match message {
+                            Some(Ok(data)) if data.is_notification() => {
+                                // Meant for the app client, hold onto it until the debug client is gone
+                                state.notifications.push(data);
+                                Self::DebugMode { state, debug_client }
+                            }
                             Some(Ok(data)) => {
-                                let (done, _) = Self::forward(&mut state.target, &mut debug_client, Some(data)).await?;
+                                let (done, _) = Self::forward(&mut state.target, &mut debug_client, &mut state.tap, Origin::Debug, Some(data)).await?;
                                 if done {
                                     Self::Listening { state }
                                 } else {
@@ -245,21 +1354,28 @@ impl Forwarder {
                                 }
                             }
                             Some(Err(e)) => {
-                                Err(e)?
+                                Err(Self::reject(&mut state.client, e).await)?
                             }
                             None => {
-                                println!("Target disconnected");
-                                Err(Error::new(std::io::ErrorKind::Other, "Target disconnected"))?
+                                info!("Target disconnected");
+                                Err(Error::other("Target disconnected"))?
                             }
                         }
                     }
+                    // Continued from the admin interface
+                    resume = Self::session_resumed(state.options.session.as_ref()), if state.held.is_some() => {
+                        Self::resume(state, Some(debug_client), resume).await?
+                    }
                 }
             }
             Forwarder::DebugForwardingClient {
                 mut state,
                 mut debug_client,
-            } => match Self::forward(&mut debug_client, &mut state.target, None).await {
-                Ok((done, _)) => {
+            } => match debug_client.next().await {
+                Some(Ok(data)) => {
+                    let done = data.done();
+                    state.tap.client_message(Origin::Debug, &data);
+                    state.target.send(data).await?;
                     if done {
                         Self::DebugForwardingServer {
                             state,
@@ -272,39 +1388,118 @@ impl Forwarder {
                         }
                     }
                 }
-                Err(e) => {
-                    println!("Error reading from debug client: {:?}", e);
-                    Self::DebugMode {
-                        state,
-                        debug_client,
+                message => {
+                    if let Some(Err(e)) = message {
+                        warn!(error = %e, "Error reading from debug client");
+                        Self::reject(&mut debug_client, e).await;
                     }
+                    info!("Debug client disconnected mid-command, syncing target");
+                    Self::sync_and_drain(state).await?
                 }
             },
             Forwarder::DebugForwardingServer {
                 mut state,
                 mut debug_client,
-            } => match Self::forward(&mut state.target, &mut debug_client, None).await {
-                Ok((done, _)) => {
-                    if done {
-                        Self::DebugMode {
-                            state,
-                            debug_client,
+            } => {
+                // We go one message at a time here so notifications can be pulled out for the app.
+                // We also watch the debug socket, otherwise a debug client that goes away during a
+                // long query would only be noticed once the target finally responds.
+                let mut watch_debug_client = true;
+                let mut peek_buf = [0u8; 1];
+                loop {
+                    tokio::select! {
+                        message = state.target.next() => {
+                            break match message {
+                                Some(Ok(data)) if data.is_notification() => {
+                                    state.notifications.push(data);
+                                    Self::DebugForwardingServer {
+                                        state,
+                                        debug_client,
+                                    }
+                                }
+                                Some(Ok(data)) => {
+                                    let done = data.done();
+                                    state.tap.backend_message(Origin::Debug, &data);
+                                    match debug_client.send(data).await {
+                                        Ok(_) if done => Self::DebugMode {
+                                            state,
+                                            debug_client,
+                                        },
+                                        Ok(_) => Self::DebugForwardingServer {
+                                            state,
+                                            debug_client,
+                                        },
+                                        Err(e) => {
+                                            warn!(error = %e, "Error writing to debug client");
+                                            if done {
+                                                Self::idle(state)
+                                            } else {
+                                                Self::sync_and_drain(state).await?
+                                            }
+                                        }
+                                    }
+                                }
+                                Some(Err(e)) => Err(Self::reject(&mut state.client, e).await)?,
+                                None => {
+                                    info!("Target disconnected");
+                                    Err(Error::other("Target disconnected"))?
+                                }
+                            };
                         }
-                    } else {
-                        Self::DebugForwardingServer {
-                            state,
-                            debug_client,
+                        peeked = debug_client.get_ref().peek(&mut peek_buf), if watch_debug_client => {
+                            match peeked {
+                                Ok(0) | Err(_) => {
+                                    info!("Debug client disconnected mid-response");
+                                    break Self::sync_and_drain(state).await?;
+                                }
+                                // The debug client is pipelining, we'll read it once this response is done
+                                Ok(_) => watch_debug_client = false,
+                            }
                         }
                     }
                 }
-                Err(e) => {
-                    println!("Error reading from debug client: {:?}", e);
-                    Self::DebugMode {
-                        state,
-                        debug_client,
+            }
+            Forwarder::DebugDraining {
+                mut state,
+                cancel_at,
+            } => {
+                let message = match cancel_at {
+                    Some(cancel_at) => {
+                        match tokio::time::timeout_at(cancel_at, state.target.next()).await {
+                            Ok(message) => message,
+                            Err(_) => {
+                                // Only try once. If the cancel doesn't land we keep waiting.
+                                Self::cancel_request(&state).await;
+                                return Ok(Self::DebugDraining {
+                                    state,
+                                    cancel_at: None,
+                                });
+                            }
+                        }
                     }
+                    None => state.target.next().await,
+                };
+                if let Some(Ok(data)) = &message {
+                    state.tap.backend_message(Origin::Debug, data);
                 }
-            },
+                match message {
+                    Some(Ok(data)) if data.is_notification() => {
+                        state.notifications.push(data);
+                        Self::DebugDraining { state, cancel_at }
+                    }
+                    // Nobody is left to receive the rest of the response
+                    Some(Ok(data)) if data.done() => {
+                        info!("Finished draining debug response");
+                        Self::idle(state)
+                    }
+                    Some(Ok(_)) => Self::DebugDraining { state, cancel_at },
+                    Some(Err(e)) => Err(Self::reject(&mut state.client, e).await)?,
+                    None => {
+                        info!("Target disconnected");
+                        Err(Error::other("Target disconnected"))?
+                    }
+                }
+            }
         };
         Ok(new_state)
     }
@@ -312,116 +1507,148 @@ impl Forwarder {
     async fn forward<T, U, V>(
         client: &mut Framed<TcpStream, U>,
         target: &mut Framed<TcpStream, V>,
+        tap: &mut Tap,
+        origin: Origin,
         initial_message: Option<T>,
     ) -> Result<(bool, Option<u8>), Error>
     where
-        T: FrameInfo + std::fmt::Debug,
-        U: Decoder<Item = T>,
-        U::Error: std::fmt::Debug,
+        T: FrameInfo + Tapped + std::fmt::Debug,
+        U: Decoder<Item = T, Error = Error>,
         V: Encoder<T>,
         V::Error: std::fmt::Debug,
     {
-        Self::do_forward(client, target, initial_message, true).await
+        Self::do_forward(client, target, tap, origin, initial_message, true).await
     }
 
     async fn do_forward<T, U, V>(
         client: &mut Framed<TcpStream, U>,
         target: &mut Framed<TcpStream, V>,
+        tap: &mut Tap,
+        origin: Origin,
         initial_message: Option<T>,
         until_complete: bool,
     ) -> Result<(bool, Option<u8>), Error>
     where
-        T: FrameInfo + std::fmt::Debug,
-        U: Decoder<Item = T>,
-        U::Error: std::fmt::Debug,
+        T: FrameInfo + Tapped + std::fmt::Debug,
+        U: Decoder<Item = T, Error = Error>,
         V: Encoder<T>,
         V::Error: std::fmt::Debug,
     {
-        if let Some(data) = initial_message {
-            let done = data.done();
-            let command = data.command();
-            match target.send(data).await {
-                Ok(_) => {
-                    if done || !until_complete {
-                        return Ok((done, command));
-                    }
-                }
-                Err(e) => {
-                    println!("Error sending to target: {:?}", e);
-                    Err(Error::new(std::io::ErrorKind::Other, "Framing Error"))?;
-                }
-            };
-        }
+        // Messages are fed into the target's write buffer and only flushed at the end of a
+        // request, or when the client has nothing else ready for us. A large result set goes
+        // out in a few big writes instead of a syscall per row.
+        let mut message = initial_message.map(Ok);
         loop {
-            match client.next().await {
-                Some(Ok(data)) => {
-                    let done = data.done();
-                    let command = data.command();
-                    match target.send(data).await {
-                        Ok(_) => {
-                            if done || !until_complete {
-                                return Ok((done, command));
+            let data = match message.take() {
+                Some(data) => data,
+                None => match client.next().now_or_never() {
+                    Some(Some(data)) => data,
+                    Some(None) => {
+                        debug!("Client disconnected");
+                        return Err(Error::other("Client disconnected"));
+                    }
+                    None => {
+                        Self::flush_target(target).await?;
+                        match client.next().await {
+                            Some(data) => data,
+                            None => {
+                                debug!("Client disconnected");
+                                return Err(Error::other("Client disconnected"));
                             }
                         }
-                        Err(e) => {
-                            println!("Error sending to target: {:?}", e);
-                            Err(Error::new(std::io::ErrorKind::Other, "Framing Error"))?;
-                        }
-                    };
-                }
-                Some(Err(e)) => {
-                    println!("Error reading from client: {:?}", e);
-                    //return Err(e);
-                    return Err(Error::new(std::io::ErrorKind::Other, "Framing Error"));
-                }
-                None => {
-                    println!("Client disconnected");
-                    return Err(Error::new(std::io::ErrorKind::Other, "Client disconnected"));
+                    }
+                },
+            };
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    debug!(error = %e, "Error reading from client");
+                    return Err(e);
                 }
+            };
+            let done = data.done();
+            let command = data.command();
+            data.tap(tap, origin);
+            if let Err(e) = target.feed(data).await {
+                warn!(error = ?e, "Error sending to target");
+                Err(Error::other("Framing Error"))?;
+            }
+            if done || !until_complete {
+                Self::flush_target(target).await?;
+                return Ok((done, command));
             }
         }
     }
 
+    async fn flush_target<T, V>(target: &mut Framed<TcpStream, V>) -> Result<(), Error>
+    where
+        V: Encoder<T>,
+        V::Error: std::fmt::Debug,
+    {
+        SinkExt::<T>::flush(target).await.map_err(|e| {
+            warn!(error = ?e, "Error sending to target");
+            Error::other("Framing Error")
+        })
+    }
+
+    // Returns the label the app gave the session, if it did
     #[async_recursion::async_recursion]
-    async fn startup(client: &mut TcpStream, target: &mut TcpStream) -> Result<(), Error> {
+    async fn startup(
+        client: &mut TcpStream,
+        target: &mut TcpStream,
+        options: &Options,
+    ) -> Result<Option<String>, Error> {
         let mut client = Framed::new(client, StartupRequest);
         match client.next().await {
             Some(data) => {
-                let data = data?;
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) => return Err(Self::reject_startup(client.get_mut(), e).await),
+                };
                 match data {
                     SslOrStartup::SslRequest(payload) => {
                         target.write_all(&payload).await?;
                         let Ok(78) = target.read_u8().await else {
-                            return Err(Error::new(
-                                std::io::ErrorKind::Other,
-                                "Expected 'N' from target",
-                            ));
+                            return Err(Error::other("Expected 'N' from target"));
                         };
                         client.get_mut().write_u8(78).await?;
                         // Should be a StartupRequest now
-                        Self::startup(client.get_mut(), target).await
+                        Self::startup(client.get_mut(), target, options).await
                     }
                     SslOrStartup::StartupRequest(payload) => {
-                        //
-                        target.write(&payload).await?;
-                        Ok(())
+                        // Anything we can't parse goes to the target as it is, to refuse
+                        let Some(mut startup) = StartupMessage::parse(&payload) else {
+                            target.write_all(&payload).await?;
+                            return Ok(None);
+                        };
+                        let label = settings::take_label(&mut startup);
+                        settings::rewrite_startup(
+                            &mut startup,
+                            &options.startup_params,
+                            options.session_id,
+                        );
+                        target.write_all(&startup.encode()).await?;
+                        Ok(label)
                     }
                 }
             }
             None => {
-                println!("Client disconnected");
-                return Err(Error::new(std::io::ErrorKind::Other, "Client disconnected"));
+                debug!("Client disconnected");
+                return Err(Error::other("Client disconnected"));
             }
         }
     }
 
     #[async_recursion::async_recursion]
-    async fn fake_startup(client: &mut TcpStream) -> Result<(), Error> {
+    pub(crate) async fn fake_startup(client: &mut TcpStream) -> Result<(), Error> {
         // TODO: return all the same initial data that the main server does.
         let mut client = Framed::new(client, StartupRequest);
         match client.next().await {
             Some(data) => {
-                let data = data?;
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) => return Err(Self::reject_startup(client.get_mut(), e).await),
+                };
                 match data {
                     SslOrStartup::SslRequest(_) => {
                         client.get_mut().write_u8(78).await?;
@@ -432,41 +1659,102 @@ impl Forwarder {
                 }
             }
             None => {
-                println!("Client disconnected");
-                return Err(Error::new(std::io::ErrorKind::Other, "Client disconnected"));
+                debug!("Client disconnected");
+                return Err(Error::other("Client disconnected"));
+            }
+        }
+    }
+
+    /// Binds the first free port of the ten from the debug binding's, or any free port if there
+    /// isn't one or they're all taken. A labelled session gets the port its label had last
+    /// time, if it's free.
+    async fn bind_debug_listener(options: &Options) -> Result<TcpListener, Error> {
+        let binding = options
+            .debug_binding
+            .as_ref()
+            .and_then(|s| s.split_once(':'))
+            .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)));
+
+        if let Some(port) = options
+            .session
+            .as_ref()
+            .and_then(|session| session.preferred_debug_port())
+        {
+            let host = binding.map_or("localhost", |(host, _)| host);
+            if let Ok(listener) = TcpListener::bind(format!("{host}:{port}")).await {
+                return Ok(listener);
             }
         }
+
+        if let Some((host, port_base)) = binding {
+            for port in port_base..(port_base + 10) {
+                if let Ok(listener) = TcpListener::bind(format!("{host}:{port}")).await {
+                    return Ok(listener);
+                }
+            }
+        }
+        TcpListener::bind("localhost:0").await
     }
 
     // This is like a state machine itself.
+    // Returns the process id and secret key from BackendKeyData, if the target sent one.
+    // `settings` go to the app as ParameterStatus messages, along with the target's.
     async fn authenticate(
         client: &mut Framed<TcpStream, ForwardingClientCodec>,
         target: &mut Framed<TcpStream, ForwardingBackendCodec>,
-    ) -> Result<(), Error> {
+        settings: Vec<(Setting, String)>,
+    ) -> Result<Option<(i32, i32)>, Error> {
+        // Nothing observes authentication, passwords included
+        let mut tap = Tap::default();
         // Server sends AuthRequest
-        let (_, tag) = Self::do_forward(target, client, None, false).await?;
+        let (_, tag) = Self::do_forward(target, client, &mut tap, Origin::App, None, false).await?;
 
         if tag.is_none() || tag.is_some_and(|t| t != 82) {
             // Client sends password
-            Self::do_forward(client, target, None, false).await?;
+            Self::do_forward(client, target, &mut tap, Origin::App, None, false).await?;
         }
 
-        // Server sends ReadyForQuery
-        Self::forward(target, client, None).await?;
-        Ok(())
+        // Server sends ParameterStatus, BackendKeyData and then ReadyForQuery
+        let mut backend_key = None;
+        loop {
+            match target.next().await {
+                Some(Ok(data)) => {
+                    if let Some(key) = data.backend_key_data() {
+                        backend_key = Some(key);
+                    }
+                    let done = data.done();
+                    if done {
+                        for (setting, value) in &settings {
+                            let status = BackendMessage::ParameterStatus {
+                                name: setting.name().to_string(),
+                                value: value.clone(),
+                            };
+                            client.feed(status.encode()).await?;
+                        }
+                    }
+                    client.send(data).await?;
+                    if done {
+                        return Ok(backend_key);
+                    }
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Err(Error::other("Target disconnected")),
+            }
+        }
     }
 
-    async fn fake_authenticate(
+    pub(crate) async fn fake_authenticate(
         client: &mut Framed<TcpStream, ForwardingClientCodec>,
     ) -> Result<(), Error> {
         // Server sends AuthRequest
         client
-            .get_mut()
-            .write(&mut [82, 0, 0, 0, 8, 0, 0, 0, 0])
+            .feed(BackendMessage::AuthenticationOk.encode())
             .await?;
 
         // Server sends ReadyForQuery
-        client.get_mut().write(&[90, 0, 0, 0, 5, 73]).await?;
+        client
+            .send(BackendMessage::ReadyForQuery { status: b'I' }.encode())
+            .await?;
         Ok(())
     }
 }
@@ -1,3 +1,16 @@
+pub mod admin;
+pub mod breakpoints;
 pub mod forwarder;
 pub mod listener;
-mod pg_codec;
+pub mod metrics;
+pub mod mock;
+mod notifications;
+mod passthrough;
+pub mod pcap;
+pub mod pg_codec;
+pub mod query_log;
+pub mod replay;
+pub mod session;
+mod settings;
+pub mod tap;
+pub mod transcript;
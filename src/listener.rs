@@ -1,18 +1,67 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{oneshot, Mutex},
     task,
 };
+use tracing::{error, field, info, info_span, warn, Instrument};
 
+use crate::admin::{self, Admin};
+use crate::breakpoints::Breakpoints;
 use crate::forwarder;
+use crate::metrics::Metrics;
+use crate::query_log::QueryLog;
+use crate::session::Sessions;
 
+#[derive(Default)]
 pub struct Config {
     pub binding: String,
     pub target_address: String,
     pub ch: Option<oneshot::Sender<()>>,
     pub debug_binding: Option<String>,
+    pub notification_buffer_size: Option<usize>,
+    pub drain_cancel_after: Option<Duration>,
+    pub max_client_message_size: Option<usize>,
+    pub max_backend_message_size: Option<usize>,
+    pub stream_threshold: Option<usize>,
+    pub passthrough: Option<bool>,
+    /// File to write the query log to, "-" for stdout
+    pub query_log: Option<String>,
+    /// Where to serve the admin interface (metrics, sessions), if anywhere
+    pub admin_binding: Option<String>,
+    /// Record a transcript of every session
+    pub record: Option<bool>,
+    /// Directory transcripts are written to, the working directory by default
+    pub record_dir: Option<String>,
+    /// Directory to write a pcap file of each session's traffic to
+    pub pcap_dir: Option<String>,
+    /// Regexes for the SQL (or prepared statement name) of app requests to pause at, each
+    /// optionally followed by `{if}` and conditions on the parameters, see `Breakpoints::new`
+    pub breakpoints: Vec<String>,
+    /// Hold errors from the target back from the app until continued
+    pub break_on_error: Option<bool>,
+    /// Hold app requests that commit until continued or rolled back
+    pub break_on_commit: Option<bool>,
+    /// SQL to run after each app request, with the results logged
+    pub watches: Vec<String>,
+    /// Let the app stop itself with `pgdproxy.break()` or a `/* pgdproxy:break */` comment
+    pub app_breaks: Option<bool>,
+    /// Send the app its debug port and session id as ParameterStatus messages when it connects
+    pub report_settings: Option<bool>,
+    /// Startup parameters to set for every session, as `NAME=VALUE`, overriding the app's.
+    /// `{session}` in the value is the session id.
+    pub set_startup_params: Vec<String>,
+    /// Startup parameters to leave out of every session's startup
+    pub remove_startup_params: Vec<String>,
 }
 
 pub struct Listener;
@@ -21,26 +70,125 @@ impl Listener {
     /// Starts our listener. This will fire on Config.ch once we're ready to accept connections
     pub async fn start(config: Config) -> Result<(), Box<dyn std::error::Error>> {
         let target_address = config.target_address.clone();
+        let mut options = forwarder::Options {
+            debug_binding: config.debug_binding.clone(),
+            ..Default::default()
+        };
+        if let Some(size) = config.notification_buffer_size {
+            options.notification_buffer_size = size;
+        }
+        if let Some(after) = config.drain_cancel_after {
+            options.drain_cancel_after = after;
+        }
+        if let Some(size) = config.max_client_message_size {
+            options.max_client_message_size = size;
+        }
+        if let Some(size) = config.max_backend_message_size {
+            options.max_backend_message_size = size;
+        }
+        if let Some(size) = config.stream_threshold {
+            options.stream_threshold = size;
+        }
+        if let Some(passthrough) = config.passthrough {
+            options.passthrough = passthrough;
+        }
+        if let Some(path) = &config.query_log {
+            options.query_log = Some(QueryLog::open(path)?);
+        }
+        options.pcap_dir = config.pcap_dir.as_ref().map(PathBuf::from);
+        options.breakpoints = Breakpoints::new(&config.breakpoints)?;
+        if let Some(break_on_error) = config.break_on_error {
+            options.break_on_error = break_on_error;
+        }
+        if let Some(break_on_commit) = config.break_on_commit {
+            options.break_on_commit = break_on_commit;
+        }
+        options.watches = config.watches.clone();
+        if let Some(app_breaks) = config.app_breaks {
+            options.app_breaks = app_breaks;
+        }
+        if let Some(report_settings) = config.report_settings {
+            options.report_settings = report_settings;
+        }
+        for name in &config.remove_startup_params {
+            options.startup_params.push((name.clone(), None));
+        }
+        for param in &config.set_startup_params {
+            match param.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    options
+                        .startup_params
+                        .push((name.to_string(), Some(value.to_string())));
+                }
+                _ => Err(format!("Startup parameter {param:?} isn't NAME=VALUE"))?,
+            }
+        }
+        let sessions = Sessions::new(config.record_dir.as_deref().unwrap_or("."));
+        if config.record == Some(true) {
+            sessions.record_all(true).await?;
+        }
+        if let Some(binding) = &config.admin_binding {
+            let metrics = Metrics::new();
+            options.metrics = Some(metrics.clone());
+            let admin_listener = TcpListener::bind(binding).await?;
+            task::spawn(admin::serve(
+                admin_listener,
+                Admin {
+                    metrics,
+                    sessions: sessions.clone(),
+                },
+            ));
+        }
         let listener = TcpListener::bind(&config.binding).await?;
         if let Some(ch) = config.ch {
             ch.send(()).or(Err("Oneshot Failed"))?;
         }
+        let session_ids = AtomicU64::new(1);
         loop {
             match listener.accept().await {
-                Ok((socket, _)) => {
+                Ok((socket, peer)) => {
                     let target_address = target_address.clone();
-                    let debug_binding = config.debug_binding.clone();
-                    task::spawn(async move {
-                        let target = TcpStream::connect(target_address).await.unwrap();
-
-                        match forwarder::Forwarder::start(socket, target, debug_binding).await {
-                            Ok(_) => {}
-                            Err(e) => println!("Error: {}", e),
-                        };
-                    });
+                    let mut options = options.clone();
+                    options.session_id = session_ids.fetch_add(1, Ordering::Relaxed);
+                    options.session = Some(sessions.open(options.session_id, peer).await);
+                    let sessions = sessions.clone();
+                    let span = info_span!(
+                        "session",
+                        id = options.session_id,
+                        peer = %peer,
+                        label = field::Empty,
+                        backend_pid = field::Empty,
+                        debug_port = field::Empty,
+                    );
+                    if let Some(metrics) = &options.metrics {
+                        metrics.session_opened();
+                    }
+                    task::spawn(
+                        async move {
+                            info!("Client connected");
+                            let session_id = options.session_id;
+                            let metrics = options.metrics.clone();
+                            match TcpStream::connect(&target_address).await {
+                                Ok(target) => {
+                                    match forwarder::Forwarder::start(socket, target, options).await {
+                                        Ok(_) => {}
+                                        Err(e) => info!(reason = %e, "Session closed"),
+                                    }
+                                }
+                                Err(e) => {
+                                    error!(error = %e, target = %target_address, "Error connecting to target");
+                                }
+                            }
+                            sessions.close(session_id).await;
+                            if let Some(metrics) = metrics {
+                                metrics.session_closed();
+                            }
+                        }
+                        .instrument(span),
+                    );
                 }
                 Err(e) => {
-                    println!("Error accepting connection: {}", e);
+                    warn!(error = %e, "Error accepting connection");
                 }
             }
         }
@@ -48,8 +196,9 @@ impl Listener {
 }
 
 /// This can be used to get the debug_port associated with a given connection
-/// NOTE: this may not work if you have any other proxies in between
-#[derive(Clone)]
+/// NOTE: this may not work if you have any other proxies in between. The app can ask the proxy
+/// with `SHOW pgdproxy.debug_port` instead.
+#[derive(Clone, Default)]
 pub struct PortMapper {
     inner: Arc<Mutex<HashMap<u16, u16>>>,
 }
@@ -66,12 +215,12 @@ impl PortMapper {
     }
 
     pub async fn lookup_debug_port(&self, client: u16) -> Option<u16> {
-        self.inner.lock().await.get(&client).map(|x| *x)
+        self.inner.lock().await.get(&client).copied()
     }
 
     /// When all else fails, you can use this to get all the debug ports
     /// and enumerate them to find the one you want
     pub async fn get_all_debug_ports(&self) -> Vec<u16> {
-        self.inner.lock().await.values().map(|x| *x).collect()
+        self.inner.lock().await.values().copied().collect()
     }
 }
@@ -0,0 +1,294 @@
+use std::{fmt::Write, sync::Arc, time::Instant};
+
+use postgres_protocol::message::backend;
+use prometheus_client::{
+    encoding::{text, EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+use crate::pg_codec::{ClientCommand, ForwardingBackendData, FrameInfo};
+use crate::tap::{Direction, Origin};
+
+impl EncodeLabelValue for Direction {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        encoder.write_str(match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        })
+    }
+}
+
+impl EncodeLabelValue for Origin {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        encoder.write_str(match self {
+            Origin::App => "app",
+            Origin::Debug => "debug",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct TrafficLabels {
+    direction: Direction,
+    origin: Origin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct OriginLabels {
+    origin: Origin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct CommandLabels {
+    command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct ErrorLabels {
+    sqlstate: String,
+}
+
+/// Everything we count, shared by the listener and every session
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    sessions_opened: Counter,
+    sessions_closed: Counter,
+    bytes: Family<TrafficLabels, Counter>,
+    queries: Family<CommandLabels, Counter>,
+    query_duration: Family<OriginLabels, Histogram>,
+    errors: Family<ErrorLabels, Counter>,
+    debug_attaches: Counter,
+    debug_duration: Histogram,
+    idle_in_transaction: Histogram,
+    notifications_buffered: Counter,
+    notifications_dropped: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("pgdproxy");
+        let sessions_opened = Counter::default();
+        registry.register(
+            "sessions_opened",
+            "Client connections accepted",
+            sessions_opened.clone(),
+        );
+        let sessions_closed = Counter::default();
+        registry.register(
+            "sessions_closed",
+            "Client connections that have ended",
+            sessions_closed.clone(),
+        );
+        let bytes = Family::<TrafficLabels, Counter>::default();
+        registry.register(
+            "bytes",
+            "Message bytes forwarded, in is towards the target",
+            bytes.clone(),
+        );
+        let queries = Family::<CommandLabels, Counter>::default();
+        registry.register(
+            "queries",
+            "Statements completed, by command tag",
+            queries.clone(),
+        );
+        let query_duration = Family::<OriginLabels, Histogram>::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(0.0005, 2.0, 16))
+        });
+        registry.register(
+            "query_duration_seconds",
+            "Time from the first message of a request to ReadyForQuery",
+            query_duration.clone(),
+        );
+        let errors = Family::<ErrorLabels, Counter>::default();
+        registry.register(
+            "errors",
+            "ErrorResponses from the target, by SQLSTATE",
+            errors.clone(),
+        );
+        let debug_attaches = Counter::default();
+        registry.register(
+            "debug_attaches",
+            "Debug clients that attached to a session",
+            debug_attaches.clone(),
+        );
+        let debug_duration = Histogram::new(exponential_buckets(0.1, 2.0, 16));
+        registry.register(
+            "debug_duration_seconds",
+            "How long debug clients stayed attached",
+            debug_duration.clone(),
+        );
+        let idle_in_transaction = Histogram::new(exponential_buckets(0.001, 2.0, 20));
+        registry.register(
+            "idle_in_transaction_seconds",
+            "How long sessions sat in a transaction between requests",
+            idle_in_transaction.clone(),
+        );
+        let notifications_buffered = Counter::default();
+        registry.register(
+            "notifications_buffered",
+            "Notifications held for the app while a debug client was attached",
+            notifications_buffered.clone(),
+        );
+        let notifications_dropped = Counter::default();
+        registry.register(
+            "notifications_dropped",
+            "Notifications lost because the app's buffer was full",
+            notifications_dropped.clone(),
+        );
+        Metrics {
+            registry: Arc::new(registry),
+            sessions_opened,
+            sessions_closed,
+            bytes,
+            queries,
+            query_duration,
+            errors,
+            debug_attaches,
+            debug_duration,
+            idle_in_transaction,
+            notifications_buffered,
+            notifications_dropped,
+        }
+    }
+
+    pub fn session_opened(&self) {
+        self.sessions_opened.inc();
+    }
+
+    pub fn session_closed(&self) {
+        self.sessions_closed.inc();
+    }
+
+    pub fn notification_buffered(&self) {
+        self.notifications_buffered.inc();
+    }
+
+    pub fn notification_dropped(&self) {
+        self.notifications_dropped.inc();
+    }
+
+    /// The OpenMetrics text exposition of everything
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String can't fail
+        let _ = text::encode(&mut out, &self.registry);
+        out
+    }
+}
+
+/// Feeds one session's traffic into `Metrics`
+#[derive(Debug)]
+pub(crate) struct MetricsTracker {
+    metrics: Metrics,
+    // When the request in flight started
+    started: Option<Instant>,
+    // When the session last went idle inside a transaction
+    idle_since: Option<Instant>,
+    debug_since: Option<Instant>,
+}
+
+impl MetricsTracker {
+    pub fn new(metrics: Metrics) -> Self {
+        MetricsTracker {
+            metrics,
+            started: None,
+            idle_since: None,
+            debug_since: None,
+        }
+    }
+
+    pub fn client_message(&mut self, origin: Origin, data: &ClientCommand) {
+        self.count_bytes(Direction::In, origin, data.len());
+        self.request_started();
+    }
+
+    /// Times the request from its first message, ending the idle time before it
+    pub fn request_started(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+        if let Some(idle_since) = self.idle_since.take() {
+            self.metrics
+                .idle_in_transaction
+                .observe(idle_since.elapsed().as_secs_f64());
+        }
+    }
+
+    pub fn backend_message(&mut self, origin: Origin, data: &ForwardingBackendData) {
+        self.count_bytes(Direction::Out, origin, data.as_bytes().len());
+        self.response(origin, data);
+    }
+
+    /// Counts the statement, error or end of request a response message is, if it's one
+    pub fn response(&mut self, origin: Origin, data: &ForwardingBackendData) {
+        match data.command() {
+            Some(backend::COMMAND_COMPLETE_TAG) => {
+                if let Ok(Some(backend::Message::CommandComplete(body))) = data.message() {
+                    let command = body.tag().map(command_name).unwrap_or_default();
+                    self.metrics
+                        .queries
+                        .get_or_create(&CommandLabels { command })
+                        .inc();
+                }
+            }
+            Some(backend::ERROR_RESPONSE_TAG) => {
+                let sqlstate = data.sqlstate().unwrap_or_default();
+                self.metrics
+                    .errors
+                    .get_or_create(&ErrorLabels { sqlstate })
+                    .inc();
+            }
+            Some(backend::READY_FOR_QUERY_TAG) => {
+                if let Some(started) = self.started.take() {
+                    self.metrics
+                        .query_duration
+                        .get_or_create(&OriginLabels { origin })
+                        .observe(started.elapsed().as_secs_f64());
+                }
+                if matches!(data.transaction_status(), Some(b'T') | Some(b'E')) {
+                    self.idle_since = Some(Instant::now());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn debug_attached(&mut self) {
+        self.metrics.debug_attaches.inc();
+        self.debug_since = Some(Instant::now());
+    }
+
+    pub fn debug_detached(&mut self) {
+        if let Some(debug_since) = self.debug_since.take() {
+            self.metrics
+                .debug_duration
+                .observe(debug_since.elapsed().as_secs_f64());
+        }
+    }
+
+    pub fn count_bytes(&self, direction: Direction, origin: Origin, len: usize) {
+        self.metrics
+            .bytes
+            .get_or_create(&TrafficLabels { direction, origin })
+            .inc_by(len as u64);
+    }
+}
+
+/// The command part of a CommandComplete tag, without the row counts, e.g. "INSERT" for
+/// "INSERT 0 5" or "CREATE TABLE" for itself
+fn command_name(tag: &str) -> String {
+    tag.split(' ')
+        .take_while(|word| !word.bytes().all(|b| b.is_ascii_digit()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
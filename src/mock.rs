@@ -0,0 +1,292 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tokio_util::codec::{Decoder, Framed};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::forwarder::Forwarder;
+use crate::pg_codec::{
+    BackendMessage, ForwardingBackendCodec, ForwardingClientCodec, FrontendMessage,
+};
+use crate::query_log::format_params;
+use crate::replay::exchanges;
+use crate::tap::Origin;
+use crate::transcript::Frame;
+
+// The tags that end a request, after which the client waits for a response
+const REQUEST_END_TAGS: &[u8] = b"QSHFcf";
+
+// How long a request that starts a longer recorded one waits for the rest. A client that
+// pipelined its requests sent them together, one that's waiting on an answer sends nothing.
+const PREFIX_WAIT: Duration = Duration::from_millis(200);
+
+/// One client message, as much of it as we match on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Step {
+    Query(String),
+    Parse(String),
+    /// The statement's SQL and the parameters
+    Bind(Option<String>, Vec<Option<String>>),
+    /// Anything else, by tag. Statement and portal names don't matter.
+    Other(u8),
+}
+
+/// Turns client messages into steps, following prepared statements so that a Bind matches by
+/// SQL rather than by statement name
+#[derive(Debug, Default)]
+struct Steps {
+    statements: HashMap<String, String>,
+}
+
+impl Steps {
+    fn step(&mut self, frame: &[u8]) -> Step {
+        match FrontendMessage::parse(frame) {
+            Ok(FrontendMessage::Query { query }) => Step::Query(query),
+            Ok(FrontendMessage::Parse { name, query, .. }) => {
+                self.statements.insert(name, query.clone());
+                Step::Parse(query)
+            }
+            Ok(FrontendMessage::Bind {
+                statement,
+                param_formats,
+                params,
+                ..
+            }) => Step::Bind(
+                self.statements.get(&statement).cloned(),
+                format_params(&param_formats, &params),
+            ),
+            _ => Step::Other(frame[0]),
+        }
+    }
+}
+
+/// The app's requests from a transcript, and what the target answered to each
+#[derive(Debug)]
+pub struct Recording {
+    // Requests can repeat with different answers, they're handed out in the order recorded
+    responses: HashMap<Vec<Step>, Vec<Vec<Bytes>>>,
+}
+
+impl Recording {
+    /// Only the app's side of the session is used, anything a debug client ran is left out
+    pub fn new(frames: &[Frame]) -> Self {
+        let frames: Vec<Frame> = frames
+            .iter()
+            .filter(|frame| frame.origin == Origin::App)
+            .cloned()
+            .collect();
+        let mut steps = Steps::default();
+        let mut responses: HashMap<Vec<Step>, Vec<Vec<Bytes>>> = HashMap::new();
+        for exchange in exchanges(&frames) {
+            let request = exchange
+                .request
+                .iter()
+                .map(|frame| steps.step(&frame.bytes))
+                .collect();
+            let response = exchange
+                .response
+                .iter()
+                .map(|frame| frame.bytes.clone())
+                .collect();
+            responses.entry(request).or_default().push(response);
+        }
+        Recording { responses }
+    }
+
+    /// How many distinct requests there are answers for
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    // Whether a longer request starts with these steps, so there may be more to read
+    fn is_prefix(&self, steps: &[Step]) -> bool {
+        self.responses
+            .keys()
+            .any(|request| request.len() > steps.len() && request.starts_with(steps))
+    }
+}
+
+/// Serves a recording as if it were postgres. Every connection gets the whole recording.
+pub async fn serve(listener: TcpListener, recording: Recording) {
+    let recording = Arc::new(recording);
+    let mut id = 0u64;
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                id += 1;
+                let recording = recording.clone();
+                let span = info_span!("mock", id, %peer);
+                task::spawn(
+                    async move {
+                        info!("Client connected");
+                        match handle(stream, &recording).await {
+                            Ok(()) => info!("Client disconnected"),
+                            Err(e) => debug!(error = %e, "Client connection ended"),
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            Err(e) => warn!(error = %e, "Error accepting client connection"),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, recording: &Recording) -> io::Result<()> {
+    Forwarder::fake_startup(&mut stream).await?;
+    let mut client = ForwardingClientCodec::default().framed(stream);
+    Forwarder::fake_authenticate(&mut client).await?;
+
+    let mut steps = Steps::default();
+    // How many of each request's answers have been used
+    let mut used: HashMap<Vec<Step>, usize> = HashMap::new();
+    let mut request = vec![];
+    // The tag that ended the request we're holding back, in case more of it is on its way
+    let mut held = None;
+    // After an unmatched request we skip messages up to the Sync, like postgres after an error
+    let mut failed = false;
+    loop {
+        let frame = match held {
+            Some(tag) => match tokio::time::timeout(PREFIX_WAIT, client.next()).await {
+                Ok(frame) => frame,
+                // Nothing more came, it's a request of its own
+                Err(_) => {
+                    held = None;
+                    let request = std::mem::take(&mut request);
+                    failed = answer(&mut client, recording, &mut used, request, tag).await?;
+                    continue;
+                }
+            },
+            None => client.next().await,
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+        let frame = frame?;
+        let tag = frame[0];
+        if tag == b'X' {
+            return Ok(());
+        }
+        if failed {
+            if tag == b'S' {
+                failed = false;
+                client
+                    .send(BackendMessage::ReadyForQuery { status: b'I' }.encode())
+                    .await?;
+            }
+            continue;
+        }
+        held = None;
+        request.push(steps.step(&frame));
+        if !REQUEST_END_TAGS.contains(&tag) {
+            continue;
+        }
+        // A client that didn't wait between requests was recorded as one longer request
+        if !recording.responses.contains_key(&request) && recording.is_prefix(&request) {
+            held = Some(tag);
+            continue;
+        }
+        let request = std::mem::take(&mut request);
+        failed = answer(&mut client, recording, &mut used, request, tag).await?;
+    }
+}
+
+/// Sends the recorded response to a request, or an error if there isn't one. Returns whether
+/// the rest of the request up to its Sync has to be skipped, after an error mid extended query.
+async fn answer(
+    client: &mut Framed<TcpStream, ForwardingClientCodec>,
+    recording: &Recording,
+    used: &mut HashMap<Vec<Step>, usize>,
+    request: Vec<Step>,
+    tag: u8,
+) -> io::Result<bool> {
+    match recording.responses.get(&request) {
+        Some(responses) => {
+            let n = used.entry(request).or_default();
+            // Once a request's answers run out, the last one is repeated
+            let response = &responses[(*n).min(responses.len() - 1)];
+            *n += 1;
+            send_response(client, response).await?;
+            Ok(false)
+        }
+        None => {
+            let message = unmatched(&request);
+            warn!(message, "Unmatched request");
+            client
+                .feed(BackendMessage::error("XX000", &message).encode())
+                .await?;
+            let ready = matches!(tag, b'Q' | b'S' | b'F');
+            if ready {
+                client
+                    .feed(BackendMessage::ReadyForQuery { status: b'I' }.encode())
+                    .await?;
+            }
+            client.flush().await?;
+            Ok(!ready)
+        }
+    }
+}
+
+async fn send_response(
+    client: &mut Framed<TcpStream, ForwardingClientCodec>,
+    response: &[Bytes],
+) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    for message in response {
+        buf.extend_from_slice(message);
+    }
+    // The recorded messages are whole, this just puts them in the type the client codec takes
+    let mut codec = ForwardingBackendCodec::default().with_stream_threshold(usize::MAX);
+    while let Some(data) = codec.decode(&mut buf)? {
+        client.feed(data).await?;
+    }
+    client.flush().await
+}
+
+/// The error message for a request there's no recording of, with its SQL and parameters
+fn unmatched(request: &[Step]) -> String {
+    let statements: Vec<String> = request
+        .iter()
+        .filter_map(|step| match step {
+            Step::Query(sql) | Step::Parse(sql) => Some(sql.clone()),
+            Step::Bind(sql, params) => {
+                let params: Vec<&str> = params
+                    .iter()
+                    .map(|param| param.as_deref().unwrap_or("NULL"))
+                    .collect();
+                Some(format!(
+                    "{} with parameters ({})",
+                    sql.as_deref().unwrap_or("unknown statement"),
+                    params.join(", ")
+                ))
+            }
+            Step::Other(_) => None,
+        })
+        .collect();
+    let tags: String = request
+        .iter()
+        .map(|step| match step {
+            Step::Query(_) => 'Q',
+            Step::Parse(_) => 'P',
+            Step::Bind(..) => 'B',
+            Step::Other(tag) => *tag as char,
+        })
+        .collect();
+    if statements.is_empty() {
+        format!("pgdproxy mock: no recorded response for messages {tags}")
+    } else {
+        format!(
+            "pgdproxy mock: no recorded response for: {}",
+            statements.join("; ")
+        )
+    }
+}
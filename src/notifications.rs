@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+use tracing::warn;
+
+use crate::metrics::Metrics;
+use crate::pg_codec::{ForwardingBackendData, ForwardingClientCodec};
+
+/// Holds NotificationResponse messages for the app client while a debug client owns the target
+/// connection. Postgres only delivers a notification once, so if we forwarded it to the debug
+/// client (or dropped it) the app would never see it.
+#[derive(Debug)]
+pub struct NotificationBuffer {
+    queue: VecDeque<ForwardingBackendData>,
+    capacity: usize,
+    buffered: u64,
+    dropped: u64,
+    // The same counts, across sessions
+    metrics: Option<Metrics>,
+}
+
+impl NotificationBuffer {
+    pub fn new(capacity: usize, metrics: Option<Metrics>) -> Self {
+        NotificationBuffer {
+            queue: VecDeque::new(),
+            capacity,
+            buffered: 0,
+            dropped: 0,
+            metrics,
+        }
+    }
+
+    /// Queues a notification. Once we're at capacity new notifications are dropped, so the app
+    /// still sees an unbroken prefix of what was sent.
+    pub fn push(&mut self, notification: ForwardingBackendData) {
+        if self.queue.len() >= self.capacity {
+            self.dropped += 1;
+            if let Some(metrics) = &self.metrics {
+                metrics.notification_dropped();
+            }
+            warn!(
+                capacity = self.capacity,
+                "Notification buffer full, dropping notification"
+            );
+        } else {
+            self.buffered += 1;
+            if let Some(metrics) = &self.metrics {
+                metrics.notification_buffered();
+            }
+            self.queue.push_back(notification);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Total number of notifications that have been buffered for this session
+    pub fn buffered(&self) -> u64 {
+        self.buffered
+    }
+
+    /// Total number of notifications that were dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Sends everything we're holding to the app client, oldest first. Returns how many were sent.
+    pub async fn flush(
+        &mut self,
+        client: &mut Framed<TcpStream, ForwardingClientCodec>,
+    ) -> Result<usize, std::io::Error> {
+        let count = self.queue.len();
+        while let Some(notification) = self.queue.pop_front() {
+            client.feed(notification).await?;
+        }
+        client.flush().await?;
+        Ok(count)
+    }
+}
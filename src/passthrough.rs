@@ -0,0 +1,230 @@
+use std::io;
+
+use bytes::BytesMut;
+
+use crate::forwarder::Options;
+use crate::pg_codec::{checked_len, ForwardingBackendData};
+use crate::tap::Tap;
+
+// Messages bigger than this aren't kept, even if their tag is
+const KEEP_LIMIT: usize = 64 * 1024;
+
+/// Reads just enough of a byte stream to know where each message ends. Used in passthrough
+/// mode, where bytes are copied straight between sockets without being framed.
+#[derive(Debug, Default)]
+struct MessageScanner {
+    header: [u8; 5],
+    header_len: usize,
+    remaining: usize,
+    // The largest message we let through, like the codecs
+    max_message_size: usize,
+    // Tags of the messages to keep whole, for whoever's scanning to look at
+    keep: &'static [u8],
+    keeping: bool,
+    kept: Vec<u8>,
+}
+
+impl MessageScanner {
+    fn new(max_message_size: usize, keep: &'static [u8]) -> Self {
+        MessageScanner {
+            max_message_size,
+            keep,
+            ..Default::default()
+        }
+    }
+
+    /// Walks `bytes`, calling `complete` with the tag and last byte of every message that ends
+    /// in them, and the whole message if it's one we keep (otherwise that's empty). A length
+    /// the codecs would refuse is an error, and nothing of `bytes` should be sent on.
+    fn scan(
+        &mut self,
+        mut bytes: &[u8],
+        mut complete: impl FnMut(u8, u8, &[u8]),
+    ) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.header_len < 5 {
+                let take = (5 - self.header_len).min(bytes.len());
+                self.header[self.header_len..self.header_len + take]
+                    .copy_from_slice(&bytes[..take]);
+                self.header_len += take;
+                bytes = &bytes[take..];
+                if self.header_len == 5 {
+                    let len = i32::from_be_bytes([
+                        self.header[1],
+                        self.header[2],
+                        self.header[3],
+                        self.header[4],
+                    ]);
+                    let len = checked_len(len, 4, self.max_message_size)?;
+                    self.remaining = len - 4;
+                    self.keeping = self.keep.contains(&self.header[0]) && len <= KEEP_LIMIT;
+                    self.kept.clear();
+                    if self.keeping {
+                        self.kept.extend_from_slice(&self.header);
+                    }
+                    if self.remaining == 0 {
+                        complete(self.header[0], self.header[4], &self.kept);
+                        self.header_len = 0;
+                    }
+                }
+                continue;
+            }
+            let take = self.remaining.min(bytes.len());
+            let last = bytes[take - 1];
+            if self.keeping {
+                self.kept.extend_from_slice(&bytes[..take]);
+            }
+            self.remaining -= take;
+            bytes = &bytes[take..];
+            if self.remaining == 0 {
+                complete(self.header[0], last, &self.kept);
+                self.header_len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.header_len == 0
+    }
+}
+
+/// What passthrough saw that the metrics count
+#[derive(Debug)]
+pub(crate) enum Seen {
+    /// The first message of a request
+    Request,
+    /// A response message that says how the request went: CommandComplete, ErrorResponse or
+    /// ReadyForQuery
+    Response(ForwardingBackendData),
+}
+
+/// Tracks where a passthrough session is in the protocol, so we know when it's safe to switch
+/// back to framed forwarding.
+#[derive(Debug)]
+pub struct PassthroughTracker {
+    client: MessageScanner,
+    target: MessageScanner,
+    // Requests (Query, Sync, FunctionCall) sent that haven't seen a ReadyForQuery yet
+    outstanding: usize,
+    // Extended protocol messages sent since the last Sync
+    unsynced: bool,
+    transaction_status: Option<u8>,
+    // Whether to note what the metrics count in `seen`
+    counting: bool,
+    // A request started since the last ReadyForQuery
+    requesting: bool,
+    seen: Vec<Seen>,
+    pub(crate) client_buf: Vec<u8>,
+    pub(crate) target_buf: Vec<u8>,
+}
+
+impl PassthroughTracker {
+    /// Passthrough starts between messages, but not necessarily between requests: the app can
+    /// have pipelined requests the tap saw still waiting on their responses. With metrics,
+    /// requests and the responses that say how they went are noted for them.
+    pub(crate) fn new(options: &Options, tap: &Tap) -> Self {
+        let counting = tap.is_counting();
+        PassthroughTracker {
+            client: MessageScanner::new(options.max_client_message_size, b""),
+            target: MessageScanner::new(
+                options.max_backend_message_size,
+                if counting { b"CEZ" } else { b"" },
+            ),
+            outstanding: tap.outstanding(),
+            unsynced: tap.is_unsynced(),
+            transaction_status: None,
+            counting,
+            requesting: false,
+            seen: vec![],
+            client_buf: vec![0; 16 * 1024],
+            target_buf: vec![0; 16 * 1024],
+        }
+    }
+
+    /// Bytes going from the client to the target, read into `client_buf`
+    pub fn client_bytes(&mut self, n: usize) -> io::Result<()> {
+        let buf = std::mem::take(&mut self.client_buf);
+        let result = self.scan_client(&buf[..n]);
+        self.client_buf = buf;
+        result
+    }
+
+    /// Bytes going from the target to the client, read into `target_buf`
+    pub fn target_bytes(&mut self, n: usize) -> io::Result<()> {
+        let buf = std::mem::take(&mut self.target_buf);
+        let result = self.scan_target(&buf[..n]);
+        self.target_buf = buf;
+        result
+    }
+
+    /// Bytes going from the client to the target
+    pub fn scan_client(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let PassthroughTracker {
+            client,
+            outstanding,
+            unsynced,
+            counting,
+            requesting,
+            seen,
+            ..
+        } = self;
+        client.scan(bytes, |tag, _, _| {
+            match tag {
+                b'Q' | b'F' => *outstanding += 1,
+                b'S' => {
+                    *outstanding += 1;
+                    *unsynced = false;
+                }
+                b'P' | b'B' | b'D' | b'E' | b'C' | b'H' => *unsynced = true,
+                _ => {}
+            }
+            if *counting && !*requesting {
+                *requesting = true;
+                seen.push(Seen::Request);
+            }
+        })
+    }
+
+    /// Bytes going from the target to the client
+    pub fn scan_target(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let PassthroughTracker {
+            target,
+            outstanding,
+            transaction_status,
+            requesting,
+            seen,
+            ..
+        } = self;
+        target.scan(bytes, |tag, last, kept| {
+            if tag == b'Z' {
+                *outstanding = outstanding.saturating_sub(1);
+                *transaction_status = Some(last);
+                *requesting = false;
+            }
+            if !kept.is_empty() {
+                seen.push(Seen::Response(ForwardingBackendData::whole(
+                    BytesMut::from(kept),
+                )));
+            }
+        })
+    }
+
+    /// What was noted for the metrics since the last call
+    pub(crate) fn take_seen(&mut self) -> Vec<Seen> {
+        std::mem::take(&mut self.seen)
+    }
+
+    /// True when neither side is mid-message and nothing is waiting on a response
+    pub fn is_idle(&self) -> bool {
+        self.client.at_boundary()
+            && self.target.at_boundary()
+            && self.outstanding == 0
+            && !self.unsynced
+    }
+
+    /// The status from the last ReadyForQuery, if there has been one since passthrough started
+    pub fn transaction_status(&self) -> Option<u8> {
+        self.transaction_status
+    }
+}
@@ -0,0 +1,385 @@
+//! Session traffic as a pcap file, for Wireshark and friends.
+//!
+//! The protocol is written as it was after TLS termination, wrapped in made up IPv4 and TCP
+//! headers. The app is 10.0.0.1, a debug client 10.0.0.2 and the target 10.0.0.3 on port 5432,
+//! where Wireshark's pgsql dissector looks for it. Each client gets its own TCP stream, opened
+//! with a handshake the first time it's seen.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::task;
+use tracing::warn;
+
+use crate::tap::{Direction, Origin};
+use crate::transcript::Frame;
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const IP_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+// The most a single packet can carry
+const MAX_SEGMENT: usize = 65535 - IP_HEADER_LEN - TCP_HEADER_LEN;
+
+const TARGET: ([u8; 4], u16) = ([10, 0, 0, 3], 5432);
+
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// One client's TCP stream with the target
+#[derive(Debug)]
+struct Stream {
+    client: ([u8; 4], u16),
+    // Next sequence number each way
+    client_seq: u32,
+    target_seq: u32,
+    open: bool,
+}
+
+impl Stream {
+    fn new(client: ([u8; 4], u16)) -> Self {
+        Stream {
+            client,
+            client_seq: 1000,
+            target_seq: 5000,
+            open: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Pending {
+    Packets {
+        direction: Direction,
+        origin: Origin,
+        at: SystemTime,
+        bytes: Vec<u8>,
+    },
+    Flush,
+}
+
+/// Writes a pcap from a blocking task, like `TranscriptWriter`, so the forwarder never waits on
+/// the disk. The file is flushed and closed once the writer is dropped.
+#[derive(Debug)]
+pub struct PcapWriter {
+    packets: mpsc::Sender<Pending>,
+    path: PathBuf,
+}
+
+impl PcapWriter {
+    pub async fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut pcap = task::spawn_blocking({
+            let path = path.clone();
+            move || Pcap::new(BufWriter::new(File::create(path)?))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let (packets, received) = mpsc::channel();
+        task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                let result = received.iter().try_for_each(|pending| match pending {
+                    Pending::Packets {
+                        direction,
+                        origin,
+                        at,
+                        bytes,
+                    } => pcap.write(direction, origin, at, &bytes),
+                    Pending::Flush => pcap.flush(),
+                });
+                // Dropping the receiver is how the sender finds out we've stopped
+                drop(received);
+                if let Err(e) = result.and_then(|_| pcap.flush()) {
+                    warn!(error = %e, path = %path.display(), "Error writing pcap");
+                }
+            }
+        });
+        Ok(PcapWriter { packets, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(
+        &mut self,
+        direction: Direction,
+        origin: Origin,
+        at: SystemTime,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        self.send(Pending::Packets {
+            direction,
+            origin,
+            at,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.send(Pending::Flush)
+    }
+
+    fn send(&self, pending: Pending) -> io::Result<()> {
+        self.packets
+            .send(pending)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "pcap writer stopped"))
+    }
+}
+
+/// The pcap format, written to `out`
+#[derive(Debug)]
+struct Pcap<W> {
+    out: W,
+    app: Stream,
+    debug: Stream,
+    ip_id: u16,
+}
+
+impl<W: Write> Pcap<W> {
+    fn new(mut out: W) -> io::Result<Self> {
+        // Little endian, microsecond timestamps, version 2.4
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Pcap {
+            out,
+            app: Stream::new(([10, 0, 0, 1], 40000)),
+            debug: Stream::new(([10, 0, 0, 2], 40001)),
+            ip_id: 0,
+        })
+    }
+
+    fn write(
+        &mut self,
+        direction: Direction,
+        origin: Origin,
+        at: SystemTime,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let stream = match origin {
+            Origin::App => &self.app,
+            Origin::Debug => &self.debug,
+        };
+        if !stream.open {
+            self.handshake(origin, at)?;
+        }
+        for segment in bytes.chunks(MAX_SEGMENT) {
+            self.packet(origin, direction, PSH | ACK, at, segment)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn handshake(&mut self, origin: Origin, at: SystemTime) -> io::Result<()> {
+        self.packet(origin, Direction::In, SYN, at, &[])?;
+        self.packet(origin, Direction::Out, SYN | ACK, at, &[])?;
+        self.packet(origin, Direction::In, ACK, at, &[])?;
+        match origin {
+            Origin::App => self.app.open = true,
+            Origin::Debug => self.debug.open = true,
+        }
+        Ok(())
+    }
+
+    fn packet(
+        &mut self,
+        origin: Origin,
+        direction: Direction,
+        flags: u8,
+        at: SystemTime,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let stream = match origin {
+            Origin::App => &mut self.app,
+            Origin::Debug => &mut self.debug,
+        };
+        let (src, dst, seq, ack) = match direction {
+            Direction::In => (stream.client, TARGET, stream.client_seq, stream.target_seq),
+            Direction::Out => (TARGET, stream.client, stream.target_seq, stream.client_seq),
+        };
+        // A SYN takes up a sequence number, like a byte of data
+        let advance = payload.len() as u32 + u32::from(flags & SYN != 0);
+        match direction {
+            Direction::In => stream.client_seq = stream.client_seq.wrapping_add(advance),
+            Direction::Out => stream.target_seq = stream.target_seq.wrapping_add(advance),
+        }
+        // Nothing's acknowledged until the other side's SYN
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+
+        let mut tcp = Vec::with_capacity(TCP_HEADER_LEN + payload.len());
+        tcp.extend_from_slice(&src.1.to_be_bytes());
+        tcp.extend_from_slice(&dst.1.to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push((TCP_HEADER_LEN as u8 / 4) << 4);
+        tcp.push(flags);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+        let mut pseudo = Vec::with_capacity(12);
+        pseudo.extend_from_slice(&src.0);
+        pseudo.extend_from_slice(&dst.0);
+        pseudo.extend_from_slice(&[0, 6]);
+        pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        let tcp_checksum = checksum(&[&pseudo, &tcp]);
+        tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let mut ip = Vec::with_capacity(IP_HEADER_LEN);
+        ip.extend_from_slice(&[0x45, 0]);
+        ip.extend_from_slice(&((IP_HEADER_LEN + tcp.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&self.ip_id.to_be_bytes());
+        // Don't fragment, TTL 64, TCP
+        ip.extend_from_slice(&[0x40, 0, 64, 6, 0, 0]);
+        ip.extend_from_slice(&src.0);
+        ip.extend_from_slice(&dst.0);
+        let ip_checksum = checksum(&[&ip]);
+        ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = (ip.len() + tcp.len()) as u32;
+        self.out
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.out
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&ip)?;
+        self.out.write_all(&tcp)
+    }
+}
+
+/// The internet checksum of the parts, one after the other
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => 0,
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Writes a recorded transcript out as a pcap file
+pub fn from_transcript(frames: &[Frame], path: impl Into<PathBuf>) -> io::Result<()> {
+    let mut pcap = Pcap::new(BufWriter::new(File::create(path.into())?))?;
+    for frame in frames {
+        pcap.write(frame.direction, frame.origin, frame.at, &frame.bytes)?;
+    }
+    pcap.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The (IP header, TCP segment) of each packet
+    fn packets(pcap: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut packets = vec![];
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            let packet = &rest[16..16 + len];
+            packets.push(packet.split_at(IP_HEADER_LEN));
+            rest = &rest[16 + len..];
+        }
+        packets
+    }
+
+    fn be32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn checksums_are_ones_complement_sums() {
+        // RFC 1071's example
+        assert_eq!(
+            checksum(&[&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]]),
+            !0xddf2
+        );
+        // An odd byte is padded, carries wrap around, and parts add up like one
+        assert_eq!(checksum(&[&[0x12, 0x34, 0x56]]), !0x6834);
+        assert_eq!(checksum(&[&[0xff, 0xff, 0x00, 0x02]]), !0x0002);
+        assert_eq!(
+            checksum(&[&[0x12, 0x34], &[0x56, 0x78]]),
+            checksum(&[&[0x12, 0x34, 0x56, 0x78]])
+        );
+    }
+
+    #[test]
+    fn packets_are_framed() {
+        let mut pcap = Pcap::new(vec![]).unwrap();
+        let at = UNIX_EPOCH + std::time::Duration::from_micros(1_500_002);
+        let query = b"Q\0\0\0\x0dselect 1\0";
+        pcap.write(Direction::In, Origin::App, at, query).unwrap();
+        let row = vec![b'y'; MAX_SEGMENT + 10];
+        pcap.write(Direction::Out, Origin::App, at, &row).unwrap();
+        let out = pcap.out;
+        assert_eq!(out[..4], 0xa1b2c3d4u32.to_le_bytes());
+        assert_eq!(out[20..24], LINKTYPE_RAW.to_le_bytes());
+        // Seconds and microseconds
+        assert_eq!(out[24..28], 1u32.to_le_bytes());
+        assert_eq!(out[28..32], 500_002u32.to_le_bytes());
+
+        let packets = packets(&out);
+        // The handshake, the query and the row in two
+        assert_eq!(packets.len(), 6);
+        let flags: Vec<u8> = packets.iter().map(|(_, tcp)| tcp[13]).collect();
+        assert_eq!(
+            flags,
+            [SYN, SYN | ACK, ACK, PSH | ACK, PSH | ACK, PSH | ACK]
+        );
+        for (ip, tcp) in &packets {
+            assert_eq!(ip[0], 0x45);
+            assert_eq!(
+                u16::from_be_bytes([ip[2], ip[3]]) as usize,
+                ip.len() + tcp.len()
+            );
+            // A header with its checksum in sums to nothing
+            assert_eq!(checksum(&[ip]), 0);
+            let mut pseudo = ip[12..20].to_vec();
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            assert_eq!(checksum(&[&pseudo, tcp]), 0);
+        }
+
+        let (ip, tcp) = packets[3];
+        assert_eq!(ip[12..16], [10, 0, 0, 1]);
+        assert_eq!(ip[16..20], TARGET.0);
+        assert_eq!(tcp[..4], [0x9c, 0x40, 0x15, 0x38]);
+        // The SYN took a sequence number each way
+        assert_eq!((be32(&tcp[4..]), be32(&tcp[8..])), (1001, 5001));
+        assert_eq!(&tcp[TCP_HEADER_LEN..], query);
+
+        let (_, first) = packets[4];
+        let (_, second) = packets[5];
+        assert_eq!(first.len() - TCP_HEADER_LEN, MAX_SEGMENT);
+        assert_eq!(second.len() - TCP_HEADER_LEN, 10);
+        assert_eq!(be32(&first[4..]), 5001);
+        assert_eq!(be32(&second[4..]), 5001 + MAX_SEGMENT as u32);
+        assert_eq!(be32(&second[8..]), 1001 + query.len() as u32);
+    }
+}
@@ -4,8 +4,9 @@ This file is part of the Rust PostgreSQL Native TLS Connector project.
 TODO: LICENSE and Copyright notice
  */
 
-use bytes::{Buf, BytesMut};
-use postgres_protocol::message::backend;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use postgres_protocol::message::{backend, frontend};
+use postgres_protocol::{IsNull, Oid};
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -13,9 +14,39 @@ pub trait FrameInfo {
     fn done(&self) -> bool;
     fn command(&self) -> Option<u8>;
 }
+/// Postgres won't allocate more than this for a single message (MaxAllocSize - 1)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 0x3fff_ffff;
+
+/// Backend messages bigger than this are passed along in chunks as they arrive rather than
+/// buffered whole
+pub const DEFAULT_STREAM_THRESHOLD: usize = 1024 * 1024;
+
+/// Postgres rejects startup packets larger than this, so we do too
+pub const MAX_STARTUP_PACKET_SIZE: usize = 10_000;
+
+// Every tag a frontend may send once the connection is started up
+const FRONTEND_TAGS: &[u8] = b"QPBDECSHdcfXFp";
+
+// Every tag a backend may send
+const BACKEND_TAGS: &[u8] = b"123AcCdDEGHIKnNRsStTZWvV";
+
 // Used to forward data from client to postgres
 #[derive(Debug)]
-pub struct ForwardingClientCodec;
+pub struct ForwardingClientCodec {
+    max_message_size: usize,
+}
+
+impl ForwardingClientCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        ForwardingClientCodec { max_message_size }
+    }
+}
+
+impl Default for ForwardingClientCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
 
 pub type ClientCommand = BytesMut;
 
@@ -31,13 +62,147 @@ impl FrameInfo for ClientCommand {
 
 // Used to forward data from postgres to client
 #[derive(Debug)]
-pub struct ForwardingBackendCodec;
+pub struct ForwardingBackendCodec {
+    max_message_size: usize,
+    stream_threshold: usize,
+    // Bytes left of a message we're streaming through in chunks
+    streaming: usize,
+}
+
+impl ForwardingBackendCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        ForwardingBackendCodec {
+            max_message_size,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            streaming: 0,
+        }
+    }
+
+    /// Messages larger than `stream_threshold` are emitted in chunks as the bytes arrive
+    pub fn with_stream_threshold(mut self, stream_threshold: usize) -> Self {
+        self.stream_threshold = stream_threshold;
+        self
+    }
+}
+
+impl Default for ForwardingBackendCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+/// Which part of a backend message a frame holds. Large messages are streamed through as a
+/// `Head` (which has the header) followed by `Body` chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePart {
+    Whole,
+    Head,
+    Body,
+}
 
 #[derive(Debug)]
 pub struct ForwardingBackendData {
     buf: BytesMut,
     // If request is complete, we can go back to Listening state
     request_complete: bool,
+    part: FramePart,
+}
+
+impl ForwardingBackendData {
+    /// NotificationResponse messages can arrive at any time, outside of any request
+    pub fn is_notification(&self) -> bool {
+        self.command() == Some(backend::NOTIFICATION_RESPONSE_TAG)
+    }
+
+    /// Decodes the frame. This copies the frame, so only do it for messages you care about.
+    /// Streamed chunks of a large message can't be decoded and give `None`.
+    pub fn message(&self) -> io::Result<Option<backend::Message>> {
+        if self.part != FramePart::Whole {
+            return Ok(None);
+        }
+        backend::Message::parse(&mut self.buf.clone())
+    }
+
+    /// A whole message that was read without the codec, like one passthrough picked out
+    pub fn whole(buf: BytesMut) -> Self {
+        let request_complete = buf.first() == Some(&backend::READY_FOR_QUERY_TAG);
+        ForwardingBackendData {
+            buf,
+            request_complete,
+            part: FramePart::Whole,
+        }
+    }
+
+    pub fn part(&self) -> FramePart {
+        self.part
+    }
+
+    /// The raw frame, tag and length included
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// The process id and secret key if this is a BackendKeyData message
+    pub fn backend_key_data(&self) -> Option<(i32, i32)> {
+        if self.command() != Some(backend::BACKEND_KEY_DATA_TAG) {
+            return None;
+        }
+        match self.message() {
+            Ok(Some(backend::Message::BackendKeyData(body))) => {
+                Some((body.process_id(), body.secret_key()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The transaction status if this is a ReadyForQuery message
+    pub fn transaction_status(&self) -> Option<u8> {
+        if self.command() != Some(backend::READY_FOR_QUERY_TAG) {
+            return None;
+        }
+        self.buf.get(5).copied()
+    }
+
+    /// The SQLSTATE if this is an ErrorResponse
+    pub fn sqlstate(&self) -> Option<String> {
+        if self.command() != Some(backend::ERROR_RESPONSE_TAG) {
+            return None;
+        }
+        self.error_fields()
+            .into_iter()
+            .find(|(field, _)| *field == b'C')
+            .map(|(_, value)| value)
+    }
+
+    /// The (type, value) pairs of an ErrorResponse or NoticeResponse. Empty for anything else.
+    pub fn error_fields(&self) -> Vec<(u8, String)> {
+        let tag = self.command();
+        if tag != Some(backend::ERROR_RESPONSE_TAG) && tag != Some(backend::NOTICE_RESPONSE_TAG) {
+            return vec![];
+        }
+        let mut body = MessageBody(Bytes::copy_from_slice(&self.buf[5..]));
+        let mut fields = vec![];
+        while let Ok(field) = body.u8() {
+            if field == 0 {
+                break;
+            }
+            match body.cstr() {
+                Ok(value) => fields.push((field, value)),
+                Err(_) => break,
+            }
+        }
+        fields
+    }
+
+    /// The parameter types in a ParameterDescription, `None` for anything else
+    pub fn parameter_types(&self) -> Option<Vec<Oid>> {
+        if self.command() != Some(backend::PARAMETER_DESCRIPTION_TAG) {
+            return None;
+        }
+        let mut body = MessageBody(Bytes::copy_from_slice(&self.buf[5..]));
+        let count = body.i16().ok()?;
+        (0..count).map(|_| body.u32().ok()).collect()
+    }
 }
 
 impl FrameInfo for ForwardingBackendData {
@@ -45,7 +210,10 @@ impl FrameInfo for ForwardingBackendData {
         self.request_complete
     }
     fn command(&self) -> Option<u8> {
-        backend::Header::parse(&self.buf).unwrap().map(|h| h.tag())
+        if self.part == FramePart::Body {
+            return None;
+        }
+        self.buf.first().copied()
     }
 }
 
@@ -64,14 +232,17 @@ impl Decoder for ForwardingClientCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() == 0 {
+        if src.is_empty() {
             return Ok(None);
         }
-        let _tag = src[0];
+        let tag = src[0];
+        if !FRONTEND_TAGS.contains(&tag) {
+            return Err(invalid(&format!("invalid frontend message type {tag}")));
+        }
         if src.len() < 5 {
             return Ok(None);
         }
-        let len = (&src[1..5]).get_i32() as usize;
+        let len = checked_len((&src[1..5]).get_i32(), 4, self.max_message_size)?;
         if src.len() < len + 1 {
             Ok(None)
         } else {
@@ -97,17 +268,49 @@ impl Decoder for ForwardingBackendCodec {
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
         let mut request_complete = false;
 
-        if let Some(header) = backend::Header::parse(&src)? {
-            let len = header.len() as usize + 1;
+        if self.streaming > 0 {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            let n = self.streaming.min(src.len());
+            self.streaming -= n;
+            return Ok(Some(ForwardingBackendData {
+                buf: src.split_to(n),
+                request_complete,
+                part: FramePart::Body,
+            }));
+        }
+
+        if let Some(header) = backend::Header::parse(src)? {
+            if !BACKEND_TAGS.contains(&header.tag()) {
+                return Err(invalid(&format!(
+                    "invalid backend message type {}",
+                    header.tag()
+                )));
+            }
+            let len = checked_len(header.len(), 4, self.max_message_size)? + 1;
             if header.tag() == backend::READY_FOR_QUERY_TAG {
                 request_complete = true;
             }
             if src.len() < len {
+                // Notifications are held for the app whole, so they're never streamed
+                if len > self.stream_threshold && header.tag() != backend::NOTIFICATION_RESPONSE_TAG
+                {
+                    // Pass along what we have, the rest follows as Body chunks
+                    self.streaming = len - src.len();
+                    return Ok(Some(ForwardingBackendData {
+                        buf: src.split_to(src.len()),
+                        request_complete,
+                        part: FramePart::Head,
+                    }));
+                }
+                src.reserve(len - src.len());
                 Ok(None)
             } else {
                 Ok(Some(ForwardingBackendData {
                     buf: src.split_to(len),
                     request_complete,
+                    part: FramePart::Whole,
                 }))
             }
         } else {
@@ -135,14 +338,14 @@ impl Decoder for StartupRequest {
         if src.len() < 8 {
             return Ok(None);
         }
-        let len = (&src[0..4]).get_i32() as usize;
+        let len = checked_len((&src[0..4]).get_i32(), 8, MAX_STARTUP_PACKET_SIZE)?;
         if src.len() < len {
             return Ok(None);
         }
 
         // Check if this is an SSL request
         // 0x04D2162F
-        if &src[0..8] == [0, 0, 0, 8, 0x04, 0xD2, 0x16, 0x2F] {
+        if src[0..8] == [0, 0, 0, 8, 0x04, 0xD2, 0x16, 0x2F] {
             let mut buf = src.split_to(8);
             let mut dst = [0u8; 8];
             buf.copy_to_slice(&mut dst);
@@ -153,3 +356,811 @@ impl Decoder for StartupRequest {
         }
     }
 }
+
+// Protocol 3.0, anything else (CancelRequest, GSSENCRequest) isn't a StartupMessage
+const PROTOCOL_VERSION: i32 = 196608;
+
+/// A StartupMessage's parameters, for when the proxy needs to read or change them before the
+/// target sees them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupMessage {
+    pub params: Vec<(String, String)>,
+}
+
+impl StartupMessage {
+    /// Parses a `StartupRequest` payload. Returns None if it isn't a protocol 3.0 startup.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let mut body = MessageBody(Bytes::copy_from_slice(payload));
+        body.i32().ok()?;
+        if body.i32().ok()? != PROTOCOL_VERSION {
+            return None;
+        }
+        let mut params = vec![];
+        loop {
+            let name = body.cstr().ok()?;
+            if name.is_empty() {
+                break;
+            }
+            params.push((name, body.cstr().ok()?));
+        }
+        Some(StartupMessage { params })
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_i32(0);
+        buf.put_i32(PROTOCOL_VERSION);
+        for (name, value) in &self.params {
+            put_cstr(&mut buf, name);
+            put_cstr(&mut buf, value);
+        }
+        buf.put_u8(0);
+        let len = buf.len() as i32;
+        buf[0..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Sets a parameter, replacing it if it's there
+    pub fn set(&mut self, name: &str, value: String) {
+        match self.params.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.params.push((name.to_string(), value)),
+        }
+    }
+
+    /// Removes a parameter, returning its value if it was there
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let i = self.params.iter().position(|(n, _)| n == name)?;
+        Some(self.params.remove(i).1)
+    }
+}
+
+/// A decoded frontend (client to server) message. Decoding is done on demand with
+/// `ClientFrame::message`, frames that nobody looks at are forwarded as raw bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrontendMessage {
+    Query {
+        query: String,
+    },
+    Parse {
+        name: String,
+        query: String,
+        param_types: Vec<Oid>,
+    },
+    Bind {
+        portal: String,
+        statement: String,
+        param_formats: Vec<i16>,
+        params: Vec<Option<Bytes>>,
+        result_formats: Vec<i16>,
+    },
+    Describe {
+        /// b'S' for a prepared statement, b'P' for a portal
+        variant: u8,
+        name: String,
+    },
+    Execute {
+        portal: String,
+        max_rows: i32,
+    },
+    Close {
+        /// b'S' for a prepared statement, b'P' for a portal
+        variant: u8,
+        name: String,
+    },
+    Sync,
+    Flush,
+    CopyData(Bytes),
+    CopyDone,
+    CopyFail {
+        message: String,
+    },
+    Terminate,
+    FunctionCall {
+        oid: Oid,
+        arg_formats: Vec<i16>,
+        args: Vec<Option<Bytes>>,
+        result_format: i16,
+    },
+    /// Anything we don't model, e.g. password messages. Kept as is so it can be re-encoded.
+    Other {
+        tag: u8,
+        body: Bytes,
+    },
+}
+
+impl FrontendMessage {
+    /// Parses a single complete frame, tag and length included
+    pub fn parse(frame: &[u8]) -> io::Result<Self> {
+        if frame.len() < 5 {
+            return Err(invalid("frontend message is shorter than its header"));
+        }
+        let tag = frame[0];
+        let len = (&frame[1..5]).get_i32();
+        if len < 4 || len as usize != frame.len() - 1 {
+            return Err(invalid("frontend message length does not match frame"));
+        }
+        let mut body = MessageBody(Bytes::copy_from_slice(&frame[5..]));
+        let message = match tag {
+            b'Q' => FrontendMessage::Query {
+                query: body.cstr()?,
+            },
+            b'P' => {
+                let name = body.cstr()?;
+                let query = body.cstr()?;
+                let count = body.i16()?;
+                let param_types = (0..count).map(|_| body.u32()).collect::<io::Result<_>>()?;
+                FrontendMessage::Parse {
+                    name,
+                    query,
+                    param_types,
+                }
+            }
+            b'B' => {
+                let portal = body.cstr()?;
+                let statement = body.cstr()?;
+                let count = body.i16()?;
+                let param_formats = (0..count).map(|_| body.i16()).collect::<io::Result<_>>()?;
+                let count = body.i16()?;
+                let params = (0..count)
+                    .map(|_| body.value())
+                    .collect::<io::Result<_>>()?;
+                let count = body.i16()?;
+                let result_formats = (0..count).map(|_| body.i16()).collect::<io::Result<_>>()?;
+                FrontendMessage::Bind {
+                    portal,
+                    statement,
+                    param_formats,
+                    params,
+                    result_formats,
+                }
+            }
+            b'D' => FrontendMessage::Describe {
+                variant: body.u8()?,
+                name: body.cstr()?,
+            },
+            b'E' => FrontendMessage::Execute {
+                portal: body.cstr()?,
+                max_rows: body.i32()?,
+            },
+            b'C' => FrontendMessage::Close {
+                variant: body.u8()?,
+                name: body.cstr()?,
+            },
+            b'S' => FrontendMessage::Sync,
+            b'H' => FrontendMessage::Flush,
+            b'd' => FrontendMessage::CopyData(body.rest()),
+            b'c' => FrontendMessage::CopyDone,
+            b'f' => FrontendMessage::CopyFail {
+                message: body.cstr()?,
+            },
+            b'X' => FrontendMessage::Terminate,
+            b'F' => {
+                let oid = body.u32()?;
+                let count = body.i16()?;
+                let arg_formats = (0..count).map(|_| body.i16()).collect::<io::Result<_>>()?;
+                let count = body.i16()?;
+                let args = (0..count)
+                    .map(|_| body.value())
+                    .collect::<io::Result<_>>()?;
+                let result_format = body.i16()?;
+                FrontendMessage::FunctionCall {
+                    oid,
+                    arg_formats,
+                    args,
+                    result_format,
+                }
+            }
+            tag => FrontendMessage::Other {
+                tag,
+                body: body.rest(),
+            },
+        };
+        Ok(message)
+    }
+
+    /// Encodes the message back into a frame that can be sent to the target
+    pub fn encode(&self) -> io::Result<ClientCommand> {
+        let mut buf = BytesMut::new();
+        match self {
+            FrontendMessage::Query { query } => frontend::query(query, &mut buf)?,
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            } => frontend::parse(name, query, param_types.iter().copied(), &mut buf)?,
+            FrontendMessage::Bind {
+                portal,
+                statement,
+                param_formats,
+                params,
+                result_formats,
+            } => frontend::bind(
+                portal,
+                statement,
+                param_formats.iter().copied(),
+                params,
+                |param, buf| match param {
+                    Some(value) => {
+                        buf.put_slice(value);
+                        Ok(IsNull::No)
+                    }
+                    None => Ok(IsNull::Yes),
+                },
+                result_formats.iter().copied(),
+                &mut buf,
+            )
+            .map_err(|e| match e {
+                frontend::BindError::Conversion(e) => invalid(&e.to_string()),
+                frontend::BindError::Serialization(e) => e,
+            })?,
+            FrontendMessage::Describe { variant, name } => {
+                frontend::describe(*variant, name, &mut buf)?
+            }
+            FrontendMessage::Execute { portal, max_rows } => {
+                frontend::execute(portal, *max_rows, &mut buf)?
+            }
+            FrontendMessage::Close { variant, name } => frontend::close(*variant, name, &mut buf)?,
+            FrontendMessage::Sync => frontend::sync(&mut buf),
+            FrontendMessage::Flush => frontend::flush(&mut buf),
+            FrontendMessage::CopyData(data) => write_message(&mut buf, b'd', |buf| {
+                buf.put_slice(data);
+            }),
+            FrontendMessage::CopyDone => frontend::copy_done(&mut buf),
+            FrontendMessage::CopyFail { message } => frontend::copy_fail(message, &mut buf)?,
+            FrontendMessage::Terminate => frontend::terminate(&mut buf),
+            FrontendMessage::FunctionCall {
+                oid,
+                arg_formats,
+                args,
+                result_format,
+            } => write_message(&mut buf, b'F', |buf| {
+                buf.put_u32(*oid);
+                buf.put_i16(arg_formats.len() as i16);
+                for format in arg_formats {
+                    buf.put_i16(*format);
+                }
+                buf.put_i16(args.len() as i16);
+                for arg in args {
+                    put_value(buf, arg.as_deref());
+                }
+                buf.put_i16(*result_format);
+            }),
+            FrontendMessage::Other { tag, body } => write_message(&mut buf, *tag, |buf| {
+                buf.put_slice(body);
+            }),
+        }
+        Ok(buf)
+    }
+}
+
+/// Typed access to client frames
+pub trait ClientFrame {
+    fn message(&self) -> io::Result<FrontendMessage>;
+}
+
+impl ClientFrame for ClientCommand {
+    fn message(&self) -> io::Result<FrontendMessage> {
+        FrontendMessage::parse(self)
+    }
+}
+
+/// Backend messages the proxy generates itself. Decoding backend frames is left to
+/// `postgres_protocol::message::backend::Message`, see `ForwardingBackendData::message`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendMessage {
+    AuthenticationOk,
+    ParameterStatus {
+        name: String,
+        value: String,
+    },
+    BackendKeyData {
+        process_id: i32,
+        secret_key: i32,
+    },
+    /// Status is b'I' (idle), b'T' (in a transaction) or b'E' (in a failed transaction)
+    ReadyForQuery {
+        status: u8,
+    },
+    /// Fields are (type, value) pairs, e.g. (b'C', "08P01") for the SQLSTATE
+    ErrorResponse {
+        fields: Vec<(u8, String)>,
+    },
+    NoticeResponse {
+        fields: Vec<(u8, String)>,
+    },
+    RowDescription {
+        fields: Vec<FieldDescription>,
+    },
+    DataRow {
+        values: Vec<Option<Bytes>>,
+    },
+    CommandComplete {
+        tag: String,
+    },
+    EmptyQueryResponse,
+    ParseComplete,
+    BindComplete,
+    CloseComplete,
+    NoData,
+    ParameterDescription {
+        types: Vec<Oid>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    pub table_oid: Oid,
+    pub column_id: i16,
+    pub type_oid: Oid,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    pub format: i16,
+}
+
+impl FieldDescription {
+    /// A text column with no table behind it, which is all we need for locally generated rows
+    pub fn text(name: &str) -> Self {
+        FieldDescription {
+            name: name.to_string(),
+            table_oid: 0,
+            column_id: 0,
+            type_oid: TEXT_OID,
+            type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+}
+
+impl BackendMessage {
+    /// An ErrorResponse with the minimum set of fields clients expect
+    pub fn error(code: &str, message: &str) -> Self {
+        Self::error_with_severity("ERROR", code, message)
+    }
+
+    /// An ErrorResponse for errors that end the session
+    pub fn fatal(code: &str, message: &str) -> Self {
+        Self::error_with_severity("FATAL", code, message)
+    }
+
+    /// A NoticeResponse, for telling a client something without failing its request
+    pub fn notice(message: &str) -> Self {
+        BackendMessage::NoticeResponse {
+            fields: vec![
+                (b'S', "NOTICE".to_string()),
+                (b'V', "NOTICE".to_string()),
+                (b'C', "00000".to_string()),
+                (b'M', message.to_string()),
+            ],
+        }
+    }
+
+    fn error_with_severity(severity: &str, code: &str, message: &str) -> Self {
+        BackendMessage::ErrorResponse {
+            fields: vec![
+                (b'S', severity.to_string()),
+                (b'V', severity.to_string()),
+                (b'C', code.to_string()),
+                (b'M', message.to_string()),
+            ],
+        }
+    }
+
+    pub fn encode(&self) -> ForwardingBackendData {
+        let mut buf = BytesMut::new();
+        let request_complete = matches!(self, BackendMessage::ReadyForQuery { .. });
+        match self {
+            BackendMessage::AuthenticationOk => {
+                write_message(&mut buf, backend::AUTHENTICATION_TAG, |buf| buf.put_i32(0))
+            }
+            BackendMessage::ParameterStatus { name, value } => {
+                write_message(&mut buf, backend::PARAMETER_STATUS_TAG, |buf| {
+                    put_cstr(buf, name);
+                    put_cstr(buf, value);
+                })
+            }
+            BackendMessage::BackendKeyData {
+                process_id,
+                secret_key,
+            } => write_message(&mut buf, backend::BACKEND_KEY_DATA_TAG, |buf| {
+                buf.put_i32(*process_id);
+                buf.put_i32(*secret_key);
+            }),
+            BackendMessage::ReadyForQuery { status } => {
+                write_message(&mut buf, backend::READY_FOR_QUERY_TAG, |buf| {
+                    buf.put_u8(*status)
+                })
+            }
+            BackendMessage::ErrorResponse { fields } => {
+                write_message(&mut buf, backend::ERROR_RESPONSE_TAG, |buf| {
+                    put_fields(buf, fields)
+                })
+            }
+            BackendMessage::NoticeResponse { fields } => {
+                write_message(&mut buf, backend::NOTICE_RESPONSE_TAG, |buf| {
+                    put_fields(buf, fields)
+                })
+            }
+            BackendMessage::RowDescription { fields } => {
+                write_message(&mut buf, backend::ROW_DESCRIPTION_TAG, |buf| {
+                    buf.put_i16(fields.len() as i16);
+                    for field in fields {
+                        put_cstr(buf, &field.name);
+                        buf.put_u32(field.table_oid);
+                        buf.put_i16(field.column_id);
+                        buf.put_u32(field.type_oid);
+                        buf.put_i16(field.type_size);
+                        buf.put_i32(field.type_modifier);
+                        buf.put_i16(field.format);
+                    }
+                })
+            }
+            BackendMessage::DataRow { values } => {
+                write_message(&mut buf, backend::DATA_ROW_TAG, |buf| {
+                    buf.put_i16(values.len() as i16);
+                    for value in values {
+                        put_value(buf, value.as_deref());
+                    }
+                })
+            }
+            BackendMessage::CommandComplete { tag } => {
+                write_message(&mut buf, backend::COMMAND_COMPLETE_TAG, |buf| {
+                    put_cstr(buf, tag)
+                })
+            }
+            BackendMessage::EmptyQueryResponse => {
+                write_message(&mut buf, backend::EMPTY_QUERY_RESPONSE_TAG, |_| {})
+            }
+            BackendMessage::ParseComplete => {
+                write_message(&mut buf, backend::PARSE_COMPLETE_TAG, |_| {})
+            }
+            BackendMessage::BindComplete => {
+                write_message(&mut buf, backend::BIND_COMPLETE_TAG, |_| {})
+            }
+            BackendMessage::CloseComplete => {
+                write_message(&mut buf, backend::CLOSE_COMPLETE_TAG, |_| {})
+            }
+            BackendMessage::NoData => write_message(&mut buf, backend::NO_DATA_TAG, |_| {}),
+            BackendMessage::ParameterDescription { types } => {
+                write_message(&mut buf, backend::PARAMETER_DESCRIPTION_TAG, |buf| {
+                    buf.put_i16(types.len() as i16);
+                    for oid in types {
+                        buf.put_u32(*oid);
+                    }
+                })
+            }
+        }
+        ForwardingBackendData {
+            buf,
+            request_complete,
+            part: FramePart::Whole,
+        }
+    }
+}
+
+pub const TEXT_OID: Oid = 25;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Validates a length read off the wire. Lengths include themselves, so anything below the
+// header size is malformed.
+pub(crate) fn checked_len(len: i32, min: usize, max: usize) -> io::Result<usize> {
+    if len < min as i32 {
+        return Err(invalid(&format!("invalid message length {len}")));
+    }
+    if len as usize > max {
+        return Err(invalid(&format!(
+            "message length {len} exceeds the maximum of {max}"
+        )));
+    }
+    Ok(len as usize)
+}
+
+// Writes tag, then length, then whatever `body` writes
+fn write_message(buf: &mut BytesMut, tag: u8, body: impl FnOnce(&mut BytesMut)) {
+    buf.put_u8(tag);
+    let start = buf.len();
+    buf.put_i32(0);
+    body(buf);
+    let len = (buf.len() - start) as i32;
+    buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn put_cstr(buf: &mut BytesMut, s: &str) {
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+}
+
+// Length prefixed value, where a length of -1 is NULL
+fn put_value(buf: &mut BytesMut, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            buf.put_i32(value.len() as i32);
+            buf.put_slice(value);
+        }
+        None => buf.put_i32(-1),
+    }
+}
+
+fn put_fields(buf: &mut BytesMut, fields: &[(u8, String)]) {
+    for (field, value) in fields {
+        buf.put_u8(*field);
+        put_cstr(buf, value);
+    }
+    buf.put_u8(0);
+}
+
+// Cursor over a message body for the frontend parser
+struct MessageBody(Bytes);
+
+impl MessageBody {
+    fn need(&self, n: usize) -> io::Result<()> {
+        if self.0.remaining() < n {
+            Err(invalid("unexpected end of message"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.need(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        self.need(2)?;
+        Ok(self.0.get_i16())
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        self.need(4)?;
+        Ok(self.0.get_i32())
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.need(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    fn cstr(&mut self) -> io::Result<String> {
+        let end = self
+            .0
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| invalid("unterminated string"))?;
+        let s = self.0.split_to(end);
+        self.0.advance(1);
+        String::from_utf8(s.to_vec()).map_err(|_| invalid("string is not valid utf-8"))
+    }
+
+    fn value(&mut self) -> io::Result<Option<Bytes>> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        self.need(len as usize)?;
+        Ok(Some(self.0.split_to(len as usize)))
+    }
+
+    fn rest(&mut self) -> Bytes {
+        self.0.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(message: FrontendMessage) {
+        let frame = message.encode().unwrap();
+        assert_eq!(frame.message().unwrap(), message);
+    }
+
+    #[test]
+    fn frontend_messages_roundtrip() {
+        roundtrip(FrontendMessage::Query {
+            query: "select 1".to_string(),
+        });
+        roundtrip(FrontendMessage::Parse {
+            name: "s1".to_string(),
+            query: "select $1".to_string(),
+            param_types: vec![23],
+        });
+        roundtrip(FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "s1".to_string(),
+            param_formats: vec![1],
+            params: vec![Some(Bytes::from_static(&[0, 0, 0, 1])), None],
+            result_formats: vec![1],
+        });
+        roundtrip(FrontendMessage::Describe {
+            variant: b'S',
+            name: "s1".to_string(),
+        });
+        roundtrip(FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        });
+        roundtrip(FrontendMessage::Close {
+            variant: b'P',
+            name: String::new(),
+        });
+        roundtrip(FrontendMessage::Sync);
+        roundtrip(FrontendMessage::Flush);
+        roundtrip(FrontendMessage::CopyData(Bytes::from_static(b"1\t2\n")));
+        roundtrip(FrontendMessage::CopyDone);
+        roundtrip(FrontendMessage::CopyFail {
+            message: "nope".to_string(),
+        });
+        roundtrip(FrontendMessage::Terminate);
+        roundtrip(FrontendMessage::FunctionCall {
+            oid: 1598,
+            arg_formats: vec![0],
+            args: vec![Some(Bytes::from_static(b"42"))],
+            result_format: 0,
+        });
+        roundtrip(FrontendMessage::Other {
+            tag: b'p',
+            body: Bytes::from_static(b"secret\0"),
+        });
+    }
+
+    #[test]
+    fn rewritten_query_reencodes() {
+        let frame = FrontendMessage::Query {
+            query: "commit".to_string(),
+        }
+        .encode()
+        .unwrap();
+        let FrontendMessage::Query { query } = frame.message().unwrap() else {
+            panic!("expected a query");
+        };
+        let frame = FrontendMessage::Query {
+            query: query.replace("commit", "rollback"),
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(&frame[..], b"Q\0\0\0\x0drollback\0");
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let mut codec = ForwardingClientCodec::new(1024);
+        // Negative length
+        let mut src = BytesMut::from(&b"Q\xff\xff\xff\xffselect"[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Over the limit
+        let mut src = BytesMut::from(&b"Q\0\x10\0\0select"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        // Unknown tag
+        let mut src = BytesMut::from(&b"z\0\0\0\x04"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        // Fine, just incomplete
+        let mut src = BytesMut::from(&b"Q\0\0\0\x0dsel"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        let mut codec = ForwardingBackendCodec::new(1024);
+        let mut src = BytesMut::from(&b"Z\0\0\0\x02"[..]);
+        assert!(codec.decode(&mut src).is_err());
+
+        let mut src = BytesMut::from(&b"\x7f\xff\xff\xff\0\x03\0\0"[..]);
+        assert!(StartupRequest.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn startup_messages_roundtrip() {
+        let payload = b"\0\0\0\x20\0\x03\0\0user\0app\0database\0shop\0\0";
+        let mut startup = StartupMessage::parse(payload).unwrap();
+        assert_eq!(startup.get("database"), Some("shop"));
+        assert_eq!(&startup.encode()[..], payload);
+
+        startup.set("user", "admin".to_string());
+        assert_eq!(startup.remove("database").as_deref(), Some("shop"));
+        let startup = StartupMessage::parse(&startup.encode()).unwrap();
+        assert_eq!(
+            startup.params,
+            vec![("user".to_string(), "admin".to_string())]
+        );
+
+        // CancelRequest
+        assert!(StartupMessage::parse(b"\0\0\0\x10\x04\xd2\x16\x2e\0\0\0\x01\0\0\0\x02").is_none());
+    }
+
+    #[test]
+    fn large_messages_are_streamed() {
+        let row = BackendMessage::DataRow {
+            values: vec![Some(Bytes::from(vec![7u8; 3000]))],
+        }
+        .encode();
+        let ready = BackendMessage::ReadyForQuery { status: b'I' }.encode();
+        let mut wire = BytesMut::new();
+        wire.extend_from_slice(row.as_bytes());
+        wire.extend_from_slice(ready.as_bytes());
+
+        let mut codec = ForwardingBackendCodec::default().with_stream_threshold(1024);
+        let mut src = BytesMut::new();
+        let mut frames = vec![];
+        // Feed it 1000 bytes at a time, like reads off a socket would
+        for chunk in wire.chunks(1000) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(frames[0].part(), FramePart::Head);
+        assert_eq!(frames[0].command(), Some(backend::DATA_ROW_TAG));
+        assert!(frames[1..frames.len() - 1]
+            .iter()
+            .all(|f| f.part() == FramePart::Body && f.command().is_none()));
+        let streamed: Vec<u8> = frames[..frames.len() - 1]
+            .iter()
+            .flat_map(|f| f.as_bytes().to_vec())
+            .collect();
+        assert_eq!(&streamed[..], row.as_bytes());
+        let last = frames.last().unwrap();
+        assert!(last.done());
+        assert_eq!(last.part(), FramePart::Whole);
+    }
+
+    #[test]
+    fn large_notifications_are_not_streamed() {
+        let payload = "x".repeat(4000);
+        let mut wire = BytesMut::new();
+        wire.put_u8(backend::NOTIFICATION_RESPONSE_TAG);
+        wire.put_i32((4 + 4 + 2 + payload.len() + 1) as i32);
+        wire.put_i32(42);
+        wire.put_slice(b"c\0");
+        wire.put_slice(payload.as_bytes());
+        wire.put_u8(0);
+
+        let mut codec = ForwardingBackendCodec::default().with_stream_threshold(1024);
+        let mut src = BytesMut::new();
+        let mut frames = vec![];
+        for chunk in wire.chunks(1000) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].part(), FramePart::Whole);
+        assert!(frames[0].is_notification());
+        assert_eq!(frames[0].as_bytes(), &wire[..]);
+    }
+
+    #[test]
+    fn backend_messages_decode() {
+        let data = BackendMessage::DataRow {
+            values: vec![Some(Bytes::from_static(b"42")), None],
+        }
+        .encode();
+        let Some(backend::Message::DataRow(row)) = data.message().unwrap() else {
+            panic!("expected a data row");
+        };
+        // 2 columns: "42" and NULL
+        assert_eq!(row.buffer(), b"\0\0\0\x0242\xff\xff\xff\xff");
+
+        let error = BackendMessage::error("08P01", "bad message").encode();
+        assert_eq!(error.sqlstate().as_deref(), Some("08P01"));
+        assert!(!error.done());
+
+        let ready = BackendMessage::ReadyForQuery { status: b'T' }.encode();
+        assert!(ready.done());
+        assert_eq!(ready.transaction_status(), Some(b'T'));
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use postgres_protocol::{message::backend, Oid};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::pg_codec::{
+    ClientCommand, ClientFrame, ForwardingBackendData, FrameInfo, FrontendMessage,
+};
+use crate::session::Session;
+use crate::tap::Origin;
+
+/// Where query log entries go, one JSON object per line. Shared by every session.
+#[derive(Clone)]
+pub struct QueryLog {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl QueryLog {
+    /// Appends to the file at `path`, or writes to stdout if `path` is "-"
+    pub fn open(path: &str) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(LineWriter::new(file))
+        };
+        Ok(QueryLog {
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out
+            .write_all(line.as_bytes())
+            .and_then(|_| out.write_all(b"\n"))
+        {
+            warn!(error = %e, "Error writing query log");
+        }
+    }
+}
+
+impl fmt::Debug for QueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryLog").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// When the request started
+    ts: String,
+    session_id: u64,
+    origin: Origin,
+    sql: Option<String>,
+    /// Prepared statement name, for the extended protocol. Empty for the unnamed statement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    params: Vec<Option<String>>,
+    rows: Option<u64>,
+    sqlstate: Option<String>,
+    /// From the first message of the request to ReadyForQuery
+    duration_ms: f64,
+    // A simple Query can run several statements, each with its own CommandComplete
+    #[serde(skip)]
+    simple: bool,
+}
+
+/// The result of a watch query, which the proxy runs after an app request
+#[derive(Debug, Serialize)]
+struct WatchEntry<'a> {
+    ts: String,
+    session_id: u64,
+    /// Always "watch"
+    origin: &'static str,
+    sql: &'a str,
+    /// The app request it ran after
+    after: Option<&'a str>,
+    rows: Option<u64>,
+    sqlstate: Option<&'a str>,
+    /// The rows, in text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a [Vec<Option<String>>]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    duration_ms: f64,
+}
+
+/// A request the client ended (with a Query, Sync or FunctionCall), waiting for its
+/// ReadyForQuery
+#[derive(Debug)]
+struct Sent {
+    started: (Instant, SystemTime),
+    logging: bool,
+    // How many of the entries, after those of the requests sent before it, are this one's
+    entries: usize,
+}
+
+/// Pairs up the statements a session runs with their results, and writes them to the query log
+/// and the session's live feed when the request finishes
+#[derive(Debug)]
+pub(crate) struct QueryTracker {
+    log: Option<QueryLog>,
+    session: Option<Arc<Session>>,
+    session_id: u64,
+    // Prepared statement name to SQL. The target has one namespace, shared by both clients.
+    statements: HashMap<String, String>,
+    // Prepared statement name to its parameters' types, from its Parse or, once it's been
+    // described, the target
+    param_types: HashMap<String, Vec<Oid>>,
+    // Statements described in this request, waiting for their ParameterDescription
+    describing: VecDeque<String>,
+    // Portal name to statement name and parameters
+    portals: HashMap<String, (String, Vec<Option<String>>)>,
+    // When the request the client is sending started
+    started: Option<(Instant, SystemTime)>,
+    // Whether this request's entries are kept, decided when it starts so a tail that starts
+    // mid-request doesn't get half of one
+    logging: bool,
+    // The client can pipeline requests, the target answers them in order
+    sent: VecDeque<Sent>,
+    entries: Vec<Entry>,
+    // The request being answered's entries before this one have their result
+    completed: usize,
+    // A Parse that fails has no Execute to hang its error on
+    last_parse: Option<(Origin, String)>,
+}
+
+impl QueryTracker {
+    pub fn new(log: Option<QueryLog>, session: Option<Arc<Session>>, session_id: u64) -> Self {
+        QueryTracker {
+            log,
+            session,
+            session_id,
+            statements: HashMap::new(),
+            param_types: HashMap::new(),
+            describing: VecDeque::new(),
+            portals: HashMap::new(),
+            started: None,
+            logging: false,
+            sent: VecDeque::new(),
+            entries: vec![],
+            completed: 0,
+            last_parse: None,
+        }
+    }
+
+    /// Whether anyone is reading the entries
+    pub fn is_active(&self) -> bool {
+        self.log.is_some()
+            || self
+                .session
+                .as_ref()
+                .is_some_and(|session| session.is_tailed())
+    }
+
+    /// The SQL of a statement prepared earlier in the session
+    pub fn statement(&self, name: &str) -> Option<&str> {
+        self.statements.get(name).map(String::as_str)
+    }
+
+    /// The types of a prepared statement's parameters, 0 where it's unspecified
+    pub fn param_types(&self, name: &str) -> Option<&[Oid]> {
+        self.param_types.get(name).map(Vec::as_slice)
+    }
+
+    pub fn client_message(&mut self, origin: Origin, data: &ClientCommand) {
+        if data.command() == Some(b'X') {
+            return;
+        }
+        let Ok(message) = data.message() else {
+            return;
+        };
+        if self.started.is_none() {
+            self.started = Some((Instant::now(), SystemTime::now()));
+            self.logging = self.is_active();
+        }
+        match message {
+            FrontendMessage::Query { query } if self.logging => {
+                let mut entry = self.entry(origin, Some(query), None, vec![]);
+                entry.simple = true;
+                self.entries.push(entry);
+            }
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            } => {
+                self.statements.insert(name.clone(), query.clone());
+                self.param_types.insert(name, param_types);
+                self.last_parse = Some((origin, query));
+            }
+            FrontendMessage::Describe {
+                variant: b'S',
+                name,
+            } => self.describing.push_back(name),
+            FrontendMessage::Bind {
+                portal,
+                statement,
+                param_formats,
+                params,
+                ..
+            } => {
+                let params = if self.logging {
+                    format_params(&param_formats, &params)
+                } else {
+                    vec![]
+                };
+                self.portals.insert(portal, (statement, params));
+            }
+            FrontendMessage::Execute { portal, .. } if self.logging => {
+                let (statement, params) = self.portals.get(&portal).cloned().unwrap_or_default();
+                let sql = self.statements.get(&statement).cloned();
+                let entry = self.entry(origin, sql, Some(statement), params);
+                self.entries.push(entry);
+            }
+            FrontendMessage::Close { variant, name } => {
+                if variant == b'S' {
+                    self.statements.remove(&name);
+                    self.param_types.remove(&name);
+                } else {
+                    self.portals.remove(&name);
+                }
+            }
+            _ => {}
+        }
+        if matches!(data.command(), Some(b'Q' | b'S' | b'F')) {
+            if let Some(started) = self.started.take() {
+                let queued: usize = self.sent.iter().map(|sent| sent.entries).sum();
+                self.sent.push_back(Sent {
+                    started,
+                    logging: self.logging,
+                    entries: self.entries.len() - queued,
+                });
+            }
+        }
+    }
+
+    /// How many entries the request the target is answering has, and whether it's logged. That's
+    /// the oldest one sent, or the one being sent if it ended in a Flush.
+    fn answering(&self) -> (usize, bool) {
+        match self.sent.front() {
+            Some(sent) => (sent.entries, sent.logging),
+            None => (self.entries.len(), self.logging),
+        }
+    }
+
+    pub fn backend_message(&mut self, data: &ForwardingBackendData) {
+        let (answering, logging) = self.answering();
+        match data.command() {
+            // There are no entries for results to go with
+            Some(
+                backend::COMMAND_COMPLETE_TAG
+                | backend::EMPTY_QUERY_RESPONSE_TAG
+                | backend::PORTAL_SUSPENDED_TAG
+                | backend::ERROR_RESPONSE_TAG,
+            ) if !logging => {}
+            Some(backend::COMMAND_COMPLETE_TAG) => {
+                let rows = match data.message() {
+                    Ok(Some(backend::Message::CommandComplete(body))) => {
+                        body.tag().ok().and_then(rows_affected)
+                    }
+                    _ => None,
+                };
+                if let Some(entry) = self.entries[..answering].get_mut(self.completed) {
+                    if entry.simple {
+                        if let Some(rows) = rows {
+                            entry.rows = Some(entry.rows.unwrap_or(0) + rows);
+                        }
+                    } else {
+                        entry.rows = rows;
+                        self.completed += 1;
+                    }
+                }
+            }
+            Some(backend::EMPTY_QUERY_RESPONSE_TAG) | Some(backend::PORTAL_SUSPENDED_TAG) => {
+                if matches!(self.entries[..answering].get(self.completed), Some(entry) if !entry.simple)
+                {
+                    self.completed += 1;
+                }
+            }
+            Some(backend::ERROR_RESPONSE_TAG) => {
+                if self.completed == answering {
+                    // Nothing was executing, so this is a failed Parse (or Bind)
+                    let (origin, sql) = match self.last_parse.take() {
+                        Some((origin, sql)) => (origin, Some(sql)),
+                        None => (Origin::App, None),
+                    };
+                    let entry = self.entry(origin, sql, None, vec![]);
+                    self.entries.insert(answering, entry);
+                    if let Some(sent) = self.sent.front_mut() {
+                        sent.entries += 1;
+                    }
+                }
+                self.entries[self.completed].sqlstate = data.sqlstate();
+                // Anything after this up to the Sync is skipped by the target
+                self.completed += 1;
+            }
+            Some(backend::PARAMETER_DESCRIPTION_TAG) => {
+                if let (Some(name), Some(types)) =
+                    (self.describing.pop_front(), data.parameter_types())
+                {
+                    self.param_types.insert(name, types);
+                }
+            }
+            Some(backend::READY_FOR_QUERY_TAG) => {
+                // Describes after an error were skipped
+                self.describing.clear();
+                self.finish();
+            }
+            _ => {}
+        }
+    }
+
+    fn entry(
+        &self,
+        origin: Origin,
+        sql: Option<String>,
+        statement: Option<String>,
+        params: Vec<Option<String>>,
+    ) -> Entry {
+        Entry {
+            ts: String::new(),
+            session_id: self.session_id,
+            origin,
+            sql,
+            statement,
+            params,
+            rows: None,
+            sqlstate: None,
+            duration_ms: 0.0,
+            simple: false,
+        }
+    }
+
+    /// Emits the entries of the request a ReadyForQuery ended, the oldest one sent
+    fn finish(&mut self) {
+        let completed = self.completed;
+        self.completed = 0;
+        // Like the startup's, or one sent in passthrough
+        let Some(sent) = self.sent.pop_front() else {
+            return;
+        };
+        if self.sent.is_empty() {
+            self.last_parse = None;
+        }
+        let (instant, time) = sent.started;
+        let duration_ms = instant.elapsed().as_secs_f64() * 1000.0;
+        let ts = humantime::format_rfc3339_micros(time).to_string();
+        let active = self.is_active();
+        let entries: Vec<Entry> = self.entries.drain(..sent.entries).collect();
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            // Statements after an error never ran
+            if i >= completed && !entry.simple {
+                continue;
+            }
+            if !active {
+                continue;
+            }
+            entry.ts = ts.clone();
+            entry.duration_ms = duration_ms;
+            self.emit(&entry);
+        }
+    }
+
+    /// Logs a watch query's rows, or its SQLSTATE and error message
+    pub fn watch(
+        &self,
+        sql: &str,
+        after: Option<&str>,
+        result: Result<&[Vec<Option<String>>], (&str, &str)>,
+        duration: Duration,
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let (rows, error) = match result {
+            Ok(rows) => (Some(rows), None),
+            Err(error) => (None, Some(error)),
+        };
+        self.emit(&WatchEntry {
+            ts: humantime::format_rfc3339_micros(SystemTime::now()).to_string(),
+            session_id: self.session_id,
+            origin: "watch",
+            sql,
+            after,
+            rows: rows.map(|rows| rows.len() as u64),
+            sqlstate: error.map(|(sqlstate, _)| sqlstate),
+            result: rows,
+            error: error.map(|(_, message)| message),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+        });
+    }
+
+    fn emit(&self, entry: &impl Serialize) {
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Error serializing query log entry");
+                return;
+            }
+        };
+        if let Some(log) = &self.log {
+            log.write(&line);
+        }
+        if let Some(session) = &self.session {
+            session.publish(line);
+        }
+    }
+}
+
+/// The row count at the end of a CommandComplete tag, like the 5 in "INSERT 0 5"
+fn rows_affected(tag: &str) -> Option<u64> {
+    tag.rsplit(' ').next()?.parse().ok()
+}
+
+/// A Bind's parameters as strings, see `format_param`
+pub(crate) fn format_params(
+    param_formats: &[i16],
+    params: &[Option<Bytes>],
+) -> Vec<Option<String>> {
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let format = param_format(param_formats, i);
+            param.as_ref().map(|value| format_param(format, value))
+        })
+        .collect()
+}
+
+/// The format of a Bind's `i`th parameter: none given means all text, one means all the same
+pub(crate) fn param_format(param_formats: &[i16], i: usize) -> i16 {
+    match param_formats.len() {
+        0 => 0,
+        1 => param_formats[0],
+        _ => param_formats.get(i).copied().unwrap_or(0),
+    }
+}
+
+/// Text parameters as they are, binary ones as hex
+pub(crate) fn format_param(format: i16, value: &Bytes) -> String {
+    if format == 0 {
+        return String::from_utf8_lossy(value).into_owned();
+    }
+    let mut hex = String::from("\\x");
+    for byte in value.iter() {
+        hex.push_str(&format!("{byte:02x}"));
+    }
+    hex
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use futures::{SinkExt, StreamExt};
+use postgres_protocol::{
+    authentication::{self, sasl},
+    message::{backend, frontend},
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::pg_codec::{ForwardingBackendCodec, FrontendMessage};
+use crate::tap::Direction;
+use crate::transcript::Frame;
+
+// How long we wait on a response that the recording doesn't end with a ReadyForQuery
+const UNSYNCED_RESPONSE_WAIT: Duration = Duration::from_secs(1);
+
+/// Where and how to replay a transcript
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub target_address: String,
+    pub user: String,
+    pub database: String,
+    pub password: Option<String>,
+    /// Compare the rows returned too, not just the command tags and errors
+    pub compare_rows: bool,
+}
+
+/// One request from the transcript (the client messages up to the next response) and the
+/// response that was recorded for it
+#[derive(Debug)]
+pub(crate) struct Exchange<'a> {
+    pub request: Vec<&'a Frame>,
+    pub response: Vec<&'a Frame>,
+}
+
+impl Exchange<'_> {
+    /// The SQL the request ran, for reports. `statements` holds the prepared statements seen so
+    /// far, so that a Bind to one parsed in an earlier exchange still shows its SQL.
+    pub fn sql(&self, statements: &mut HashMap<String, String>) -> Vec<String> {
+        let mut sql = vec![];
+        let mut parsed = HashSet::new();
+        for frame in &self.request {
+            match FrontendMessage::parse(&frame.bytes) {
+                Ok(FrontendMessage::Query { query }) => sql.push(query),
+                Ok(FrontendMessage::Parse { name, query, .. }) => {
+                    sql.push(query.clone());
+                    statements.insert(name.clone(), query);
+                    parsed.insert(name);
+                }
+                Ok(FrontendMessage::Bind { statement, .. }) if !parsed.contains(&statement) => {
+                    if let Some(query) = statements.get(&statement) {
+                        sql.push(query.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        sql
+    }
+}
+
+/// Splits a transcript into exchanges
+pub(crate) fn exchanges(frames: &[Frame]) -> Vec<Exchange<'_>> {
+    let mut exchanges = vec![];
+    let mut current = Exchange {
+        request: vec![],
+        response: vec![],
+    };
+    for frame in frames {
+        if frame.direction == Direction::In {
+            if !current.response.is_empty() {
+                exchanges.push(std::mem::replace(
+                    &mut current,
+                    Exchange {
+                        request: vec![],
+                        response: vec![],
+                    },
+                ));
+            }
+            current.request.push(frame);
+        } else {
+            current.response.push(frame);
+        }
+    }
+    if !current.request.is_empty() || !current.response.is_empty() {
+        exchanges.push(current);
+    }
+    // Anything the target sent before the first request, like a notification, isn't a response
+    exchanges.retain(|exchange| !exchange.request.is_empty());
+    exchanges
+}
+
+/// What we compare between the recording and the replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// A CommandComplete tag, like "INSERT 0 5"
+    Complete(String),
+    EmptyQuery,
+    /// An ErrorResponse's SQLSTATE
+    Error(String),
+    /// A DataRow's values, only compared if asked to
+    Row(Vec<Option<String>>),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Complete(tag) => write!(f, "{tag}"),
+            Outcome::EmptyQuery => write!(f, "empty query"),
+            Outcome::Error(sqlstate) => write!(f, "ERROR {sqlstate}"),
+            Outcome::Row(values) => {
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|value| value.as_deref().unwrap_or("NULL"))
+                    .collect();
+                write!(f, "row ({})", values.join(", "))
+            }
+        }
+    }
+}
+
+/// The outcomes in a response, in order
+pub(crate) fn outcomes<'a>(
+    messages: impl IntoIterator<Item = &'a [u8]>,
+    compare_rows: bool,
+) -> Vec<Outcome> {
+    messages
+        .into_iter()
+        .filter_map(|message| {
+            let body = message.get(5..)?;
+            match message[0] {
+                backend::COMMAND_COMPLETE_TAG => Some(Outcome::Complete(cstr(body))),
+                backend::EMPTY_QUERY_RESPONSE_TAG => Some(Outcome::EmptyQuery),
+                backend::ERROR_RESPONSE_TAG => Some(Outcome::Error(sqlstate(body))),
+                backend::DATA_ROW_TAG if compare_rows => Some(Outcome::Row(data_row(body))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn cstr(body: &[u8]) -> String {
+    let end = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+    String::from_utf8_lossy(&body[..end]).into_owned()
+}
+
+fn sqlstate(mut body: &[u8]) -> String {
+    while let Some((&field, rest)) = body.split_first() {
+        if field == 0 {
+            break;
+        }
+        let value = cstr(rest);
+        if field == b'C' {
+            return value;
+        }
+        body = rest.get(value.len() + 1..).unwrap_or_default();
+    }
+    String::new()
+}
+
+pub(crate) fn data_row(mut body: &[u8]) -> Vec<Option<String>> {
+    if body.len() < 2 {
+        return vec![];
+    }
+    let count = body.get_i16();
+    let mut values = vec![];
+    for _ in 0..count {
+        if body.len() < 4 {
+            break;
+        }
+        let len = body.get_i32();
+        if len < 0 {
+            values.push(None);
+            continue;
+        }
+        let len = (len as usize).min(body.len());
+        values.push(Some(String::from_utf8_lossy(&body[..len]).into_owned()));
+        body.advance(len);
+    }
+    values
+}
+
+/// Where the replay's response differed from the recorded one
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Which exchange, counting from 1
+    pub exchange: usize,
+    pub sql: Vec<String>,
+    pub expected: Vec<Outcome>,
+    pub actual: Vec<Outcome>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |outcomes: &[Outcome]| {
+            outcomes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+        writeln!(f, "exchange {}: {}", self.exchange, self.sql.join("; "))?;
+        writeln!(f, "  expected: {}", list(&self.expected))?;
+        write!(f, "  actual:   {}", list(&self.actual))
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub exchanges: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+/// Re-sends the client side of a transcript, app and debug clients alike, to a target and
+/// compares what comes back to what was recorded
+pub async fn replay(frames: &[Frame], options: &ReplayOptions) -> io::Result<Report> {
+    let mut target = connect(options).await?;
+    let exchanges = exchanges(frames);
+    let mut divergences = vec![];
+    let mut statements = HashMap::new();
+    for (i, exchange) in exchanges.iter().enumerate() {
+        let sql = exchange.sql(&mut statements);
+        for frame in &exchange.request {
+            // We hang up ourselves once we're done
+            if frame.tag() == b'X' {
+                continue;
+            }
+            target.feed(BytesMut::from(&frame.bytes[..])).await?;
+        }
+        target.flush().await?;
+
+        let expected = outcomes(
+            exchange.response.iter().map(|frame| &frame.bytes[..]),
+            options.compare_rows,
+        );
+        let syncs = exchange
+            .response
+            .iter()
+            .filter(|frame| frame.tag() == backend::READY_FOR_QUERY_TAG)
+            .count();
+        let response = read_response(&mut target, syncs, exchange.response.len()).await?;
+        let actual = outcomes(response.iter().map(|m| &m[..]), options.compare_rows);
+        if expected != actual {
+            divergences.push(Divergence {
+                exchange: i + 1,
+                sql,
+                expected,
+                actual,
+            });
+        }
+    }
+    let mut terminate = BytesMut::new();
+    frontend::terminate(&mut terminate);
+    let _ = target.send(terminate).await;
+    Ok(Report {
+        exchanges: exchanges.len(),
+        divergences,
+    })
+}
+
+/// Reads until `syncs` ReadyForQuerys. If the recording didn't have any, we take `messages`
+/// messages or whatever arrives before the target goes quiet.
+async fn read_response(
+    target: &mut Framed<TcpStream, ForwardingBackendCodec>,
+    syncs: usize,
+    messages: usize,
+) -> io::Result<Vec<BytesMut>> {
+    let mut response = vec![];
+    let mut seen = 0;
+    loop {
+        if syncs == 0 && (messages == 0 || response.len() >= messages) {
+            break;
+        }
+        let message = if syncs == 0 {
+            match tokio::time::timeout(UNSYNCED_RESPONSE_WAIT, target.next()).await {
+                Ok(message) => message,
+                Err(_) => break,
+            }
+        } else {
+            target.next().await
+        };
+        let Some(message) = message else {
+            return Err(io::Error::other("Target disconnected"));
+        };
+        let message = BytesMut::from(message?.as_bytes());
+        let tag = message[0];
+        response.push(message);
+        if tag == backend::READY_FOR_QUERY_TAG {
+            seen += 1;
+            if seen == syncs {
+                break;
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Starts up and authenticates a connection of our own
+pub async fn connect(
+    options: &ReplayOptions,
+) -> io::Result<Framed<TcpStream, ForwardingBackendCodec>> {
+    let mut stream = TcpStream::connect(&options.target_address).await?;
+    let mut buf = BytesMut::new();
+    frontend::startup_message(
+        [
+            ("user", options.user.as_str()),
+            ("database", options.database.as_str()),
+        ],
+        &mut buf,
+    )?;
+    tokio::io::AsyncWriteExt::write_all(&mut stream, &buf).await?;
+    // Streaming would split messages we want to compare whole
+    let mut target = ForwardingBackendCodec::default()
+        .with_stream_threshold(usize::MAX)
+        .framed(stream);
+    let password = options.password.as_deref().unwrap_or_default().as_bytes();
+    let mut scram = None;
+    loop {
+        let Some(message) = target.next().await else {
+            return Err(io::Error::other("Target disconnected during startup"));
+        };
+        let message = message?;
+        let mut buf = BytesMut::new();
+        match message.message()? {
+            Some(backend::Message::AuthenticationCleartextPassword) => {
+                frontend::password_message(password, &mut buf)?;
+            }
+            Some(backend::Message::AuthenticationMd5Password(body)) => {
+                let hash = authentication::md5_hash(options.user.as_bytes(), password, body.salt());
+                frontend::password_message(hash.as_bytes(), &mut buf)?;
+            }
+            Some(backend::Message::AuthenticationSasl(_)) => {
+                let client = sasl::ScramSha256::new(password, sasl::ChannelBinding::unsupported());
+                frontend::sasl_initial_response(sasl::SCRAM_SHA_256, client.message(), &mut buf)?;
+                scram = Some(client);
+            }
+            Some(backend::Message::AuthenticationSaslContinue(body)) => {
+                let Some(client) = scram.as_mut() else {
+                    return Err(io::Error::other("Unexpected SASL continue"));
+                };
+                client.update(body.data())?;
+                frontend::sasl_response(client.message(), &mut buf)?;
+            }
+            Some(backend::Message::AuthenticationSaslFinal(body)) => {
+                if let Some(client) = scram.as_mut() {
+                    client.finish(body.data())?;
+                }
+            }
+            Some(backend::Message::ErrorResponse(_)) => {
+                let message = outcomes([message.as_bytes()], false);
+                return Err(io::Error::other(format!(
+                    "Target refused the connection: {}",
+                    message.first().map(ToString::to_string).unwrap_or_default()
+                )));
+            }
+            Some(backend::Message::ReadyForQuery(_)) => return Ok(target),
+            _ => {}
+        }
+        if !buf.is_empty() {
+            target.send(buf).await?;
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch, Mutex, Notify};
+use tracing::{info, warn};
+
+use crate::tap::{Direction, Origin};
+use crate::transcript::TranscriptWriter;
+
+// How many entries a slow tail can fall behind by
+const FEED_CAPACITY: usize = 1024;
+
+/// Where an app request is being held
+#[derive(Debug, Clone, Serialize)]
+pub struct Pause {
+    #[serde(flatten)]
+    pub reason: Reason,
+    /// What the request runs
+    pub sql: String,
+    /// The parameters it runs with, when it stopped at a breakpoint or a commit
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<Option<String>>,
+}
+
+/// Why an app request is being held
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum Reason {
+    /// It matched this pattern
+    Breakpoint { breakpoint: String },
+    /// We're in step mode, every request stops
+    Step,
+    /// It commits a transaction. It can be turned into a rollback if it has the COMMIT's SQL, a
+    /// statement prepared by an earlier request can't be changed.
+    Commit { rollback: bool },
+    /// The app asked to stop here, with `pgdproxy.break()` or a `/* pgdproxy:break */` comment
+    Break { label: Option<String> },
+    /// It failed with this error, which the app hasn't seen yet. It's the response that's held.
+    Error { error: String },
+}
+
+/// What to do with a held app request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Resume {
+    /// Send it on as it is
+    #[default]
+    Continue,
+    /// Don't run it, the app gets an empty result
+    Skip,
+    /// Run this SQL instead
+    Rewrite(String),
+    /// Roll back instead of committing
+    Rollback,
+}
+
+/// What the rest of the proxy can see of, and change about, a running session
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub peer: SocketAddr,
+    debug_port: OnceLock<u16>,
+    // What the app named the session, if it did
+    label: OnceLock<String>,
+    // The debug port each label last had, shared by every session
+    label_ports: LabelPorts,
+    record_dir: PathBuf,
+    recording: std::sync::Mutex<Option<TranscriptWriter>>,
+    // Query log entries, as JSON, for anyone tailing the session
+    feed: broadcast::Sender<String>,
+    // Set while an app request is held at a breakpoint
+    pause: watch::Sender<Option<Pause>>,
+    // What to do with the held request, once it's let go
+    resume: std::sync::Mutex<Resume>,
+    // Hold every app request, not just those at breakpoints
+    stepping: AtomicBool,
+    // SQL to run after each app request, on top of the listener's
+    watches: std::sync::Mutex<Vec<String>>,
+    // Wakes the forwarder when something changed that it needs to act on, e.g. recording was
+    // turned on while it was in passthrough mode
+    changed: Notify,
+}
+
+impl Session {
+    fn new(id: u64, peer: SocketAddr, record_dir: PathBuf, label_ports: LabelPorts) -> Self {
+        Session {
+            id,
+            peer,
+            debug_port: OnceLock::new(),
+            label: OnceLock::new(),
+            label_ports,
+            record_dir,
+            recording: std::sync::Mutex::new(None),
+            feed: broadcast::channel(FEED_CAPACITY).0,
+            pause: watch::channel(None).0,
+            resume: std::sync::Mutex::new(Resume::Continue),
+            stepping: AtomicBool::new(false),
+            watches: std::sync::Mutex::new(vec![]),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn debug_port(&self) -> Option<u16> {
+        self.debug_port.get().copied()
+    }
+
+    pub(crate) fn set_debug_port(&self, port: u16) {
+        let _ = self.debug_port.set(port);
+        if let Some(label) = self.label() {
+            self.label_ports
+                .lock()
+                .unwrap()
+                .insert(label.to_string(), port);
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.get().map(String::as_str)
+    }
+
+    pub(crate) fn set_label(&self, label: String) {
+        let _ = self.label.set(label);
+    }
+
+    /// The debug port the last session with this one's label had, so a test that reconnects
+    /// can be debugged on the same port each time
+    pub(crate) fn preferred_debug_port(&self) -> Option<u16> {
+        let label = self.label()?;
+        self.label_ports.lock().unwrap().get(label).copied()
+    }
+
+    /// Starts writing a transcript of this session, if we aren't already. Returns its path.
+    pub async fn start_recording(&self) -> io::Result<PathBuf> {
+        if let Some(path) = self.recording_path() {
+            return Ok(path);
+        }
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self
+            .record_dir
+            .join(format!("{}-session-{}.pgdt", started, self.id));
+        let writer = TranscriptWriter::create(&path).await?;
+        let mut recording = self.recording.lock().unwrap();
+        // Started by someone else while we were creating ours
+        if let Some(writer) = recording.as_ref() {
+            return Ok(writer.path().to_path_buf());
+        }
+        *recording = Some(writer);
+        info!(session_id = self.id, path = %path.display(), "Recording session");
+        self.changed.notify_one();
+        Ok(path)
+    }
+
+    /// Stops recording, once the transcript is all on disk. Returns its path if we were
+    /// recording.
+    pub async fn stop_recording(&self) -> Option<PathBuf> {
+        let writer = self.recording.lock().unwrap().take()?;
+        let path = writer.path().to_path_buf();
+        writer.finish().await;
+        Some(path)
+    }
+
+    pub fn recording_path(&self) -> Option<PathBuf> {
+        let recording = self.recording.lock().unwrap();
+        recording.as_ref().map(|writer| writer.path().to_path_buf())
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.recording.lock().unwrap().is_some()
+    }
+
+    /// Adds a frame to the transcript, if we're recording. A failed write stops the recording.
+    pub(crate) fn record(&self, direction: Direction, origin: Origin, bytes: &[u8], flush: bool) {
+        let mut recording = self.recording.lock().unwrap();
+        let Some(writer) = recording.as_mut() else {
+            return;
+        };
+        let mut result = writer.write(direction, origin, bytes);
+        if flush {
+            result = result.and_then(|_| writer.flush());
+        }
+        if let Err(e) = result {
+            warn!(error = %e, path = %writer.path().display(), "Error writing transcript, stopping recording");
+            *recording = None;
+        }
+    }
+
+    /// Follows the statements this session runs, each as a query log entry in JSON. The
+    /// receiver lags, losing entries, if it falls more than a thousand or so behind.
+    pub fn tail(&self) -> broadcast::Receiver<String> {
+        let receiver = self.feed.subscribe();
+        // Passthrough doesn't see statements, the forwarder needs to start framing
+        self.changed.notify_one();
+        receiver
+    }
+
+    pub(crate) fn is_tailed(&self) -> bool {
+        self.feed.receiver_count() > 0
+    }
+
+    pub(crate) fn publish(&self, entry: String) {
+        // Nobody listening isn't an error
+        let _ = self.feed.send(entry);
+    }
+
+    pub(crate) fn pause(&self, pause: Pause) {
+        *self.resume.lock().unwrap() = Resume::Continue;
+        self.pause.send_replace(Some(pause));
+    }
+
+    /// The breakpoint the app is paused at, if it is
+    pub fn paused(&self) -> Option<Pause> {
+        self.pause.borrow().clone()
+    }
+
+    /// Lets the held app request go on, as it is or changed. Returns whether the app was paused.
+    pub fn resume(&self, resume: Resume) -> bool {
+        *self.resume.lock().unwrap() = resume;
+        self.pause.send_replace(None).is_some()
+    }
+
+    /// Resolves once the app isn't paused, with what to do with the request it was paused at
+    pub(crate) async fn resumed(&self) -> Resume {
+        let mut pause = self.pause.subscribe();
+        // The sender lives as long as we do, so this can't fail
+        let _ = pause.wait_for(Option::is_none).await;
+        self.resume.lock().unwrap().clone()
+    }
+
+    /// Turns step mode on or off. While it's on, every app request is held until continued.
+    /// Turning it off continues a request that's only held because we were stepping.
+    pub fn set_stepping(&self, on: bool) {
+        self.stepping.store(on, Ordering::Relaxed);
+        if !on {
+            self.pause.send_if_modified(|pause| {
+                let stepped = pause
+                    .as_ref()
+                    .is_some_and(|pause| pause.reason == Reason::Step);
+                if stepped {
+                    *self.resume.lock().unwrap() = Resume::Continue;
+                    *pause = None;
+                }
+                stepped
+            });
+        }
+        // Passthrough doesn't see requests, the forwarder needs to start framing
+        self.changed.notify_one();
+    }
+
+    pub fn is_stepping(&self) -> bool {
+        self.stepping.load(Ordering::Relaxed)
+    }
+
+    /// Runs `sql` after each app request, logging what it returns
+    pub fn watch(&self, sql: String) {
+        self.watches.lock().unwrap().push(sql);
+        // Passthrough doesn't see requests end, the forwarder needs to start framing
+        self.changed.notify_one();
+    }
+
+    /// Stops all of this session's watches. Returns what they were.
+    pub fn unwatch(&self) -> Vec<String> {
+        std::mem::take(&mut *self.watches.lock().unwrap())
+    }
+
+    pub fn watches(&self) -> Vec<String> {
+        self.watches.lock().unwrap().clone()
+    }
+
+    /// Resolves once something changes that the forwarder might need to act on
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
+
+type LabelPorts = Arc<std::sync::Mutex<HashMap<String, u16>>>;
+
+/// Every running session, by id
+#[derive(Clone, Debug)]
+pub struct Sessions {
+    inner: Arc<Mutex<HashMap<u64, Arc<Session>>>>,
+    record_dir: PathBuf,
+    record_all: Arc<AtomicBool>,
+    label_ports: LabelPorts,
+}
+
+impl Sessions {
+    /// Transcripts are written to `record_dir`
+    pub fn new(record_dir: impl Into<PathBuf>) -> Self {
+        Sessions {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            record_dir: record_dir.into(),
+            record_all: Arc::new(AtomicBool::new(false)),
+            label_ports: Arc::default(),
+        }
+    }
+
+    pub async fn open(&self, id: u64, peer: SocketAddr) -> Arc<Session> {
+        let session = Arc::new(Session::new(
+            id,
+            peer,
+            self.record_dir.clone(),
+            self.label_ports.clone(),
+        ));
+        if self.record_all.load(Ordering::Relaxed) {
+            if let Err(e) = session.start_recording().await {
+                warn!(error = %e, "Error starting recording");
+            }
+        }
+        self.inner.lock().await.insert(id, session.clone());
+        session
+    }
+
+    pub async fn close(&self, id: u64) {
+        self.inner.lock().await.remove(&id);
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Arc<Session>> {
+        self.inner.lock().await.get(&id).cloned()
+    }
+
+    /// A session by id, or by label. Several sessions can share a label, this is the newest.
+    pub async fn find(&self, key: &str) -> Option<Arc<Session>> {
+        if let Ok(id) = key.parse() {
+            return self.get(id).await;
+        }
+        self.list()
+            .await
+            .into_iter()
+            .rev()
+            .find(|session| session.label() == Some(key))
+    }
+
+    /// All sessions, oldest first
+    pub async fn list(&self) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<_> = self.inner.lock().await.values().cloned().collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Turns recording on or off for every session, including ones that haven't started yet
+    pub async fn record_all(&self, on: bool) -> io::Result<()> {
+        self.record_all.store(on, Ordering::Relaxed);
+        for session in self.list().await {
+            if on {
+                session.start_recording().await?;
+            } else {
+                session.stop_recording().await;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::pg_codec::{BackendMessage, FieldDescription, FrontendMessage, StartupMessage};
+
+/// Settings only the proxy knows, which the app can `SHOW` like any other. Postgres has never
+/// heard of them, so the proxy answers for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Setting {
+    /// The port to attach a debug client to this session on
+    DebugPort,
+    /// The session's id in the admin interface and the logs
+    SessionId,
+}
+
+// Anything longer isn't a SHOW of ours, and isn't worth decoding to find out
+const MAX_SHOW_LEN: usize = 128;
+
+impl Setting {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::DebugPort => "pgdproxy.debug_port",
+            Setting::SessionId => "pgdproxy.session_id",
+        }
+    }
+
+    /// The setting a frame shows, if it's a simple Query like `SHOW pgdproxy.debug_port`
+    pub fn shown(frame: &[u8]) -> Option<Self> {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        if frame.first() != Some(&b'Q') || frame.len() > MAX_SHOW_LEN {
+            return None;
+        }
+        let FrontendMessage::Query { query } = FrontendMessage::parse(frame).ok()? else {
+            return None;
+        };
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(r"(?i)^\s*show\s+pgdproxy\.(debug_port|session_id)\s*;?\s*$").unwrap()
+        });
+        let captures = pattern.captures(&query)?;
+        if captures[1].eq_ignore_ascii_case("debug_port") {
+            Some(Setting::DebugPort)
+        } else {
+            Some(Setting::SessionId)
+        }
+    }
+
+    /// What postgres would answer a SHOW with, if it knew the setting
+    pub fn show(&self, value: &str, status: u8) -> Vec<BackendMessage> {
+        vec![
+            BackendMessage::RowDescription {
+                fields: vec![FieldDescription::text(self.name())],
+            },
+            BackendMessage::DataRow {
+                values: vec![Some(value.to_string().into())],
+            },
+            BackendMessage::CommandComplete {
+                tag: "SHOW".to_string(),
+            },
+            BackendMessage::ReadyForQuery { status },
+        ]
+    }
+}
+
+/// What an app names its session with, as a startup parameter of its own or as
+/// `options=-c pgdproxy.label=...`
+pub(crate) const LABEL: &str = "pgdproxy.label";
+
+/// Takes the session label out of a startup message, wherever the app put it. Postgres would
+/// refuse the connection over a setting it doesn't know, so it can't be left in.
+pub(crate) fn take_label(startup: &mut StartupMessage) -> Option<String> {
+    let mut label = startup.remove(LABEL);
+    if let Some(options) = startup.get("options") {
+        let mut kept = vec![];
+        let mut tokens = split_options(options).into_iter().peekable();
+        while let Some((raw, token)) = tokens.next() {
+            let setting = if token == "-c" {
+                match tokens.peek() {
+                    Some((_, next))
+                        if next.split_once('=').is_some_and(|(name, _)| name == LABEL) =>
+                    {
+                        let (_, next) = tokens.next().unwrap();
+                        Some(next)
+                    }
+                    _ => None,
+                }
+            } else {
+                token
+                    .strip_prefix("-c")
+                    .or_else(|| token.strip_prefix("--"))
+                    .map(str::to_string)
+            };
+            match setting.as_deref().and_then(|s| s.split_once('=')) {
+                Some((LABEL, value)) => {
+                    label = label.or(Some(value.to_string()));
+                }
+                _ => kept.push(raw),
+            }
+        }
+        if kept.is_empty() {
+            startup.remove("options");
+        } else {
+            startup.set("options", kept.join(" "));
+        }
+    }
+    label.filter(|label| !label.is_empty())
+}
+
+// Splits `options` the way postgres does: on whitespace, with a backslash escaping the next
+// character. Each token comes back as it was written, and unescaped.
+fn split_options(options: &str) -> Vec<(String, String)> {
+    let mut tokens = vec![];
+    let mut chars = options.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return tokens;
+        }
+        let (mut raw, mut token) = (String::new(), String::new());
+        while let Some(c) = chars.next_if(|c| !c.is_ascii_whitespace()) {
+            raw.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    raw.push(escaped);
+                    token.push(escaped);
+                }
+            } else {
+                token.push(c);
+            }
+        }
+        tokens.push((raw, token));
+    }
+}
+
+/// Sets or removes startup parameters as the listener was configured to, e.g. to name every
+/// session `application_name=pgdproxy:{session}`
+pub(crate) fn rewrite_startup(
+    startup: &mut StartupMessage,
+    params: &[(String, Option<String>)],
+    session_id: u64,
+) {
+    for (name, value) in params {
+        match value {
+            Some(value) => {
+                startup.set(name, value.replace("{session}", &session_id.to_string()));
+            }
+            None => {
+                startup.remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn startup(params: &[(&str, &str)]) -> StartupMessage {
+        StartupMessage {
+            params: params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn options_are_split_like_postgres() {
+        let tokens = split_options("  -c a=b\t--x=y\\ z -cq=\\\\  ");
+        assert_eq!(
+            tokens,
+            [
+                ("-c".to_string(), "-c".to_string()),
+                ("a=b".to_string(), "a=b".to_string()),
+                ("--x=y\\ z".to_string(), "--x=y z".to_string()),
+                ("-cq=\\\\".to_string(), "-cq=\\".to_string()),
+            ]
+        );
+        assert!(split_options(" ").is_empty());
+        // A trailing backslash escapes nothing
+        assert_eq!(split_options("a\\"), [("a\\".to_string(), "a".to_string())]);
+    }
+
+    #[test]
+    fn labels_are_taken_from_the_startup() {
+        let mut message = startup(&[("user", "u"), (LABEL, "param")]);
+        assert_eq!(take_label(&mut message).as_deref(), Some("param"));
+        assert_eq!(message.params, startup(&[("user", "u")]).params);
+
+        for options in [
+            "-c pgdproxy.label=my\\ test",
+            "-cpgdproxy.label=my\\ test",
+            "--pgdproxy.label=my\\ test",
+        ] {
+            let mut message = startup(&[("options", options)]);
+            assert_eq!(
+                take_label(&mut message).as_deref(),
+                Some("my test"),
+                "{options}"
+            );
+            assert!(message.params.is_empty(), "{options}");
+        }
+
+        // Other options are kept as they were written
+        let mut message = startup(&[(
+            "options",
+            "-c work_mem=4MB -c pgdproxy.label=a --search_path=x\\ y -c",
+        )]);
+        assert_eq!(take_label(&mut message).as_deref(), Some("a"));
+        assert_eq!(
+            message.get("options"),
+            Some("-c work_mem=4MB --search_path=x\\ y -c")
+        );
+
+        // The parameter wins over the options
+        let mut message = startup(&[(LABEL, "param"), ("options", "--pgdproxy.label=option")]);
+        assert_eq!(take_label(&mut message).as_deref(), Some("param"));
+        assert_eq!(message.get("options"), None);
+
+        let mut message = startup(&[("options", "-c pgdproxy.labels=x"), (LABEL, "")]);
+        assert_eq!(take_label(&mut message), None);
+        assert_eq!(message.get("options"), Some("-c pgdproxy.labels=x"));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use postgres_protocol::Oid;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::forwarder::Options;
+use crate::metrics::MetricsTracker;
+use crate::passthrough::{PassthroughTracker, Seen};
+use crate::pcap::PcapWriter;
+use crate::pg_codec::{ClientCommand, ForwardingBackendData, FrameInfo};
+use crate::query_log::QueryTracker;
+use crate::session::Session;
+
+/// Which client a message came from, or is headed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    App,
+    Debug,
+}
+
+/// Which way a message was going
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// From the app or debug client to the target
+    In,
+    /// From the target to the app or debug client
+    Out,
+}
+
+/// Sees every framed message on its way through the forwarder. Anything that only observes
+/// traffic, rather than steering it, hangs off of this.
+#[derive(Debug, Default)]
+pub(crate) struct Tap {
+    // Feeds the query log and the session's live tail
+    query_log: Option<QueryTracker>,
+    metrics: Option<MetricsTracker>,
+    // Writes the transcript, when the session is being recorded
+    session: Option<Arc<Session>>,
+    pcap: Option<PcapWriter>,
+    // From the last ReadyForQuery, for when we answer a debug client ourselves
+    transaction_status: Option<u8>,
+    // App requests (Query, Sync, FunctionCall) sent that haven't seen their ReadyForQuery yet
+    outstanding: usize,
+    // The app sent extended protocol messages since its last Sync
+    unsynced: bool,
+    // Same, for the debug client
+    debug_unsynced: bool,
+}
+
+impl Tap {
+    pub async fn new(options: &Options) -> Self {
+        Tap {
+            // Breakpoints look up statements prepared earlier, so a registered session always
+            // tracks them. Entries are only built while there's a query log or a tail to read
+            // them.
+            query_log: (options.query_log.is_some() || options.session.is_some()).then(|| {
+                QueryTracker::new(
+                    options.query_log.clone(),
+                    options.session.clone(),
+                    options.session_id,
+                )
+            }),
+            metrics: options.metrics.clone().map(MetricsTracker::new),
+            session: options.session.clone(),
+            pcap: match &options.pcap_dir {
+                Some(dir) => Self::create_pcap(dir, options.session_id).await,
+                None => None,
+            },
+            transaction_status: None,
+            outstanding: 0,
+            unsynced: false,
+            debug_unsynced: false,
+        }
+    }
+
+    async fn create_pcap(dir: &Path, session_id: u64) -> Option<PcapWriter> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{}-session-{}.pcap", started, session_id));
+        match PcapWriter::create(&path).await {
+            Ok(writer) => {
+                info!(path = %path.display(), "Writing pcap");
+                Some(writer)
+            }
+            Err(e) => {
+                warn!(error = %e, path = %path.display(), "Error creating pcap");
+                None
+            }
+        }
+    }
+
+    /// The SQL of a statement prepared earlier in the session, if we saw it happen
+    pub fn statement(&self, name: &str) -> Option<&str> {
+        self.query_log.as_ref()?.statement(name)
+    }
+
+    /// The types of a prepared statement's parameters, if we saw it prepared
+    pub fn param_types(&self, name: &str) -> Option<&[Oid]> {
+        self.query_log.as_ref()?.param_types(name)
+    }
+
+    /// Idle unless we've seen otherwise
+    pub fn transaction_status(&self) -> u8 {
+        self.transaction_status.unwrap_or(b'I')
+    }
+
+    /// Logs the result of a watch query, see `QueryTracker::watch`
+    pub(crate) fn watch(
+        &self,
+        sql: &str,
+        after: Option<&str>,
+        result: Result<&[Vec<Option<String>>], (&str, &str)>,
+        duration: Duration,
+    ) {
+        if let Some(query_log) = &self.query_log {
+            query_log.watch(sql, after, result, duration);
+        }
+    }
+
+    /// For ReadyForQuery we don't tap, like the answer to one of the proxy's own queries
+    pub(crate) fn set_transaction_status(&mut self, status: u8) {
+        self.transaction_status = Some(status);
+    }
+
+    /// Whether the app has nothing on the wire: every request it sent has had its
+    /// ReadyForQuery. Only then can the target be used for anything else.
+    pub fn is_app_idle(&self) -> bool {
+        self.outstanding == 0 && !self.unsynced
+    }
+
+    /// App requests still waiting on their ReadyForQuery
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    /// Whether the app sent extended protocol messages since its last Sync
+    pub fn is_unsynced(&self) -> bool {
+        self.unsynced
+    }
+
+    /// Whether the debug client sent extended protocol messages since its last Sync, so the
+    /// target won't answer them until it gets one
+    pub fn is_debug_unsynced(&self) -> bool {
+        self.debug_unsynced
+    }
+
+    /// Passthrough hands back at an idle point, having seen the messages we didn't
+    pub(crate) fn passthrough_ended(&mut self, transaction_status: Option<u8>) {
+        self.outstanding = 0;
+        self.unsynced = false;
+        if transaction_status.is_some() {
+            self.transaction_status = transaction_status;
+        }
+    }
+
+    /// Whether passthrough should note what the metrics count
+    pub fn is_counting(&self) -> bool {
+        self.metrics.is_some()
+    }
+
+    /// Passthrough doesn't frame messages, but the bytes it copies still count, and so do the
+    /// requests and responses the tracker noted in them
+    pub(crate) fn passthrough(
+        &mut self,
+        direction: Direction,
+        len: usize,
+        tracker: &mut PassthroughTracker,
+    ) {
+        let Some(metrics) = &mut self.metrics else {
+            return;
+        };
+        metrics.count_bytes(direction, Origin::App, len);
+        for seen in tracker.take_seen() {
+            match seen {
+                Seen::Request => metrics.request_started(),
+                Seen::Response(data) => metrics.response(Origin::App, &data),
+            }
+        }
+    }
+
+    /// Whether anything here needs to see messages. If so we can't use passthrough mode. Metrics
+    /// don't, passthrough picks out what they count.
+    pub fn is_active(&self) -> bool {
+        self.query_log.as_ref().is_some_and(QueryTracker::is_active)
+            || self.pcap.is_some()
+            || self
+                .session
+                .as_ref()
+                .is_some_and(|session| session.is_recording())
+    }
+
+    pub fn client_message(&mut self, origin: Origin, data: &ClientCommand) {
+        if origin == Origin::App {
+            match data.command() {
+                Some(b'Q' | b'F') => self.outstanding += 1,
+                Some(b'S') => {
+                    self.outstanding += 1;
+                    self.unsynced = false;
+                }
+                Some(b'P' | b'B' | b'D' | b'E' | b'C' | b'H') => self.unsynced = true,
+                _ => {}
+            }
+        } else {
+            match data.command() {
+                Some(b'Q' | b'S') => self.debug_unsynced = false,
+                Some(b'P' | b'B' | b'D' | b'E' | b'C' | b'H') => self.debug_unsynced = true,
+                _ => {}
+            }
+        }
+        if let Some(query_log) = &mut self.query_log {
+            query_log.client_message(origin, data);
+        }
+        if let Some(metrics) = &mut self.metrics {
+            metrics.client_message(origin, data);
+        }
+        // Nothing follows a Terminate, so that's as good a time to flush as any
+        let last = data.command() == Some(b'X');
+        if let Some(session) = &self.session {
+            session.record(Direction::In, origin, data, last);
+        }
+        self.write_pcap(Direction::In, origin, data, last);
+    }
+
+    pub fn backend_message(&mut self, origin: Origin, data: &ForwardingBackendData) {
+        if let Some(status) = data.transaction_status() {
+            self.transaction_status = Some(status);
+            if origin == Origin::App {
+                self.outstanding = self.outstanding.saturating_sub(1);
+            }
+        }
+        if let Some(query_log) = &mut self.query_log {
+            query_log.backend_message(data);
+        }
+        if let Some(metrics) = &mut self.metrics {
+            metrics.backend_message(origin, data);
+        }
+        if let Some(session) = &self.session {
+            // Flushing at the end of each request keeps the transcript useful if we crash
+            session.record(Direction::Out, origin, data.as_bytes(), data.done());
+        }
+        self.write_pcap(Direction::Out, origin, data.as_bytes(), data.done());
+    }
+
+    /// A failed write stops the pcap, like it does a recording
+    fn write_pcap(&mut self, direction: Direction, origin: Origin, bytes: &[u8], flush: bool) {
+        let Some(writer) = &mut self.pcap else {
+            return;
+        };
+        let mut result = writer.write(direction, origin, SystemTime::now(), bytes);
+        if flush {
+            result = result.and_then(|_| writer.flush());
+        }
+        if let Err(e) = result {
+            warn!(error = %e, path = %writer.path().display(), "Error writing pcap, stopping it");
+            self.pcap = None;
+        }
+    }
+
+    pub fn debug_attached(&mut self) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.debug_attached();
+        }
+    }
+
+    pub fn debug_detached(&mut self) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.debug_detached();
+        }
+    }
+}
+
+/// Lets the generic forwarding code hand either kind of frame to the tap
+pub(crate) trait Tapped {
+    fn tap(&self, tap: &mut Tap, origin: Origin);
+}
+
+impl Tapped for ClientCommand {
+    fn tap(&self, tap: &mut Tap, origin: Origin) {
+        tap.client_message(origin, self);
+    }
+}
+
+impl Tapped for ForwardingBackendData {
+    fn tap(&self, tap: &mut Tap, origin: Origin) {
+        tap.backend_message(origin, self);
+    }
+}
@@ -0,0 +1,250 @@
+//! Session transcripts, every frame of a session in order.
+//!
+//! The file starts with `PGDT` and a version byte. Each frame follows as a header of direction
+//! (`F` towards the target, `B` from it), origin (`a` app, `d` debug), microseconds since the
+//! epoch (u64) and length (u32), all big endian, and then the raw message. Large backend messages
+//! that were streamed through in chunks are written as they went, one record per chunk.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::task::{self, JoinHandle};
+use tracing::warn;
+
+use crate::pg_codec::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::tap::{Direction, Origin};
+
+const MAGIC: &[u8; 5] = b"PGDT\x01";
+
+/// One message from a transcript
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub direction: Direction,
+    pub origin: Origin,
+    pub at: SystemTime,
+    /// The whole message, tag and length included
+    pub bytes: Bytes,
+}
+
+impl Frame {
+    pub fn tag(&self) -> u8 {
+        self.bytes[0]
+    }
+}
+
+#[derive(Debug)]
+enum Pending {
+    Record(Vec<u8>),
+    Flush,
+}
+
+/// Writes a transcript from a blocking task, so the forwarder never waits on the disk. The file
+/// is flushed and closed once the writer is finished or dropped.
+#[derive(Debug)]
+pub struct TranscriptWriter {
+    records: mpsc::Sender<Pending>,
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl TranscriptWriter {
+    pub async fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut out = task::spawn_blocking({
+            let path = path.clone();
+            move || -> io::Result<_> {
+                let mut out = BufWriter::new(File::create(path)?);
+                out.write_all(MAGIC)?;
+                Ok(out)
+            }
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let (records, received) = mpsc::channel();
+        let task = task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                let result = received.iter().try_for_each(|write| match write {
+                    Pending::Record(record) => out.write_all(&record),
+                    Pending::Flush => out.flush(),
+                });
+                // Dropping the receiver is how the sender finds out we've stopped
+                drop(received);
+                if let Err(e) = result.and_then(|_| out.flush()) {
+                    warn!(error = %e, path = %path.display(), "Error writing transcript");
+                }
+            }
+        });
+        Ok(TranscriptWriter {
+            records,
+            path,
+            task,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, direction: Direction, origin: Origin, bytes: &[u8]) -> io::Result<()> {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut header = [0u8; 14];
+        header[0] = match direction {
+            Direction::In => b'F',
+            Direction::Out => b'B',
+        };
+        header[1] = match origin {
+            Origin::App => b'a',
+            Origin::Debug => b'd',
+        };
+        header[2..10].copy_from_slice(&micros.to_be_bytes());
+        header[10..14].copy_from_slice(&(bytes.len() as u32).to_be_bytes());
+        let mut record = Vec::with_capacity(header.len() + bytes.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(bytes);
+        self.send(Pending::Record(record))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.send(Pending::Flush)
+    }
+
+    /// Waits for everything written so far to be on disk
+    pub async fn finish(self) {
+        drop(self.records);
+        let _ = self.task.await;
+    }
+
+    fn send(&self, write: Pending) -> io::Result<()> {
+        self.records
+            .send(write)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "transcript writer stopped"))
+    }
+}
+
+/// Reads a whole transcript. Streamed chunks are put back together into whole messages.
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Frame>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 5];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a pgdproxy transcript"));
+    }
+    let mut frames: Vec<Frame> = vec![];
+    // A backend message that was streamed, its index and how many bytes it's still missing
+    let mut streamed: Option<(usize, usize)> = None;
+    let mut partial = BytesMut::new();
+    loop {
+        let mut header = [0u8; 14];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            // A transcript that was cut off mid-record still has everything before it
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut header = &header[..];
+        let direction = match header.get_u8() {
+            b'F' => Direction::In,
+            b'B' => Direction::Out,
+            _ => return Err(invalid("unknown direction in transcript")),
+        };
+        let origin = match header.get_u8() {
+            b'a' => Origin::App,
+            b'd' => Origin::Debug,
+            _ => return Err(invalid("unknown origin in transcript")),
+        };
+        let at = UNIX_EPOCH + Duration::from_micros(header.get_u64());
+        let size = header.get_u32() as usize;
+        // Streamed chunks are never bigger than the message they're part of
+        if size > DEFAULT_MAX_MESSAGE_SIZE + 1 {
+            return Err(invalid("transcript frame is too long"));
+        }
+        let mut bytes = vec![0u8; size];
+        match input.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        if let (Some((index, missing)), Direction::Out) = (streamed, direction) {
+            let missing = missing.saturating_sub(bytes.len());
+            partial.extend_from_slice(&bytes);
+            if missing == 0 {
+                frames[index].bytes = partial.split().freeze();
+                streamed = None;
+            } else {
+                streamed = Some((index, missing));
+            }
+            continue;
+        }
+        if bytes.len() < 5 {
+            return Err(invalid("transcript frame is shorter than its header"));
+        }
+        let len = match (&bytes[1..5]).get_i32() {
+            len @ 4.. => 1 + len as usize,
+            _ => return Err(invalid("invalid message length in transcript")),
+        };
+        // Only backend messages were streamed, anything else has to be whole
+        if len < bytes.len() || (direction == Direction::In && len != bytes.len()) {
+            return Err(invalid("transcript frame doesn't match its message length"));
+        }
+        if direction == Direction::Out && len > bytes.len() {
+            streamed = Some((frames.len(), len - bytes.len()));
+            partial = BytesMut::from(&bytes[..]);
+        }
+        frames.push(Frame {
+            direction,
+            origin,
+            at,
+            bytes: bytes.into(),
+        });
+    }
+    Ok(frames)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_record(tag: u8, len: i32, body: &[u8]) -> io::Result<Vec<Frame>> {
+        let mut message = vec![tag];
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(body);
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(b"Fa");
+        file.extend_from_slice(&0u64.to_be_bytes());
+        file.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        file.extend_from_slice(&message);
+        let path = std::env::temp_dir().join(format!(
+            "pgdproxy-{}-transcript-{tag}-{len}.pgdt",
+            std::process::id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let frames = read(&path);
+        let _ = std::fs::remove_file(&path);
+        frames
+    }
+
+    #[test]
+    fn message_lengths_are_checked() {
+        let frames = read_record(b'Q', 6, b"x\0").unwrap();
+        assert_eq!(&frames[0].bytes[..], b"Q\0\0\0\x06x\0");
+        for len in [-1, 3, 5, 7, i32::MAX] {
+            let e = read_record(b'Q', len, b"x\0").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "length {len}");
+        }
+    }
+}